@@ -0,0 +1,351 @@
+// Fan calibration
+// PWM is stepped through its range while RPM is measured, result is kept in state file
+// and used by daemon to know what RPM fan should have at every speed
+
+use core::Device;
+use core::Propeller;
+use core::Settings;
+use core::Thermometer;
+use core::read_file;
+use control;
+use dsys;
+use signal;
+
+use serde_json;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// PWM step in percents
+const STEP: usize = 5;
+/// Calibration is aborted when temperature is this close to temp_crit
+pub const CRIT_MARGIN: usize = 5;
+/// RPM is averaged over this many last seconds of every step
+const RPM_SAMPLES: usize = 3;
+/// Suggested pwm_min is this much above start speed
+const MIN_MARGIN: usize = 5;
+
+/// What fan does at every speed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// Lowest PWM fan keeps spinning at while slowing down
+    pub spin_min: usize,
+    /// Lowest PWM that starts stopped fan
+    pub start_min: usize,
+    /// Steady RPM for PWM, PWM is growing
+    pub rpm: Vec<(usize, usize)>,
+}
+
+impl Calibration {
+    /// Fan speed that is safe to use as pwm_min, stopped fan would start at it
+    pub fn pwm_min(&self) -> usize {
+        (self.start_min.max(self.spin_min) + MIN_MARGIN).min(100)
+    }
+
+    /// Expected RPM for PWM, interpolated between measured points
+    pub fn rpm_at(&self, pwm: usize) -> Option<usize> {
+        let mut prev: Option<(usize, usize)> = None;
+        for &(p, rpm) in &self.rpm {
+            if pwm <= p {
+                return Some(match prev {
+                    None => rpm,
+                    Some((pp, prpm)) if p > pp => {
+                        (prpm as isize + (rpm as isize - prpm as isize) * (pwm - pp) as isize /
+                             (p - pp) as isize) as usize
+                    }
+                    Some(_) => rpm,
+                });
+            }
+            prev = Some((p, rpm));
+        }
+        prev.map(|(_, rpm)| rpm)
+    }
+
+    fn to_json(&self, dev: &Device) -> Value {
+        json!({
+            "device": format!("{}#{}", dev.dev_type, dev.id),
+            "name": dev.name,
+            "spin_min": self.spin_min,
+            "start_min": self.start_min,
+            "rpm": self.rpm,
+        })
+    }
+
+    fn from_json(v: &Value) -> Option<Calibration> {
+        let rpm = v["rpm"]
+            .as_array()?
+            .iter()
+            .map(|p| Some((p[0].as_u64()? as usize, p[1].as_u64()? as usize)))
+            .collect::<Option<Vec<(usize, usize)>>>()?;
+        Some(Calibration {
+            spin_min: v["spin_min"].as_u64()? as usize,
+            start_min: v["start_min"].as_u64()? as usize,
+            rpm,
+        })
+    }
+}
+
+/// Key of device PWM in state file, PCI slot is used if known, so it survives reboots
+pub fn key(dev: &Device, set: &Settings) -> String {
+    let pwm = set.sys_pwm_file.to_string_lossy();
+    match dev.pci {
+        Some(ref pci) => format!("{}/{}", pci, pwm),
+        None => format!("{}#{}/{}", dev.dev_type, dev.id, pwm),
+    }
+}
+
+/// Read all calibrations from state file, missing file means nothing is calibrated yet
+pub fn load(path: &Path) -> Result<BTreeMap<String, Calibration>, String> {
+    let pstr = path.to_string_lossy();
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let text = read_file(&path.to_path_buf()).map_err(|e| format!("Can not read {} {}", pstr, e))?;
+    let v: Value = serde_json::from_str(&text).map_err(|e| format!("Invalid {} {}", pstr, e))?;
+
+    let mut res = BTreeMap::new();
+    for (k, c) in v.as_object().ok_or(format!("Invalid {}, expected object", pstr))? {
+        match Calibration::from_json(c) {
+            Some(cal) => {
+                res.insert(k.clone(), cal);
+            }
+            None => println!("WARNING {} invalid calibration of {} is ignored", pstr, k),
+        }
+    }
+    Ok(res)
+}
+
+/// Add or replace calibration of device in state file
+pub fn save(path: &Path, dev: &Device, set: &Settings, cal: &Calibration) -> Result<(), String> {
+    let pstr = path.to_string_lossy();
+    let mut state: Value = match read_file(&path.to_path_buf()) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Invalid {} {}", pstr, e))?,
+        Err(_) => json!({}),
+    };
+    state[key(dev, set)] = cal.to_json(dev);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Can not create {} {}", dir.to_string_lossy(), e))?;
+    }
+    let text = serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?;
+    fs::write(path, text + "\n").map_err(|e| format!("Can not write {} {}", pstr, e))
+}
+
+/// Fan and thermometers of device under calibration
+struct Bench {
+    name: String,
+    prop: Box<dyn Propeller>,
+    terms: Vec<Box<dyn Thermometer>>,
+    temp_abort: usize,
+    settle: Duration,
+}
+
+impl Bench {
+    /// Set PWM, wait until fan settles and return its average RPM.
+    /// Error if temperature gets close to critical or signal was received
+    fn step(&self, pwm: usize) -> Result<usize, String> {
+        self.prop.pwm_set(pwm)?;
+
+        let secs = self.settle.as_secs().max(RPM_SAMPLES as u64);
+        let mut rpms: Vec<usize> = Vec::new();
+        let mut temp = 0;
+        for _ in 0..secs {
+            thread::sleep(Duration::from_secs(1));
+            if let Some(sig) = signal::take() {
+                return Err(format!("Received {}", signal::name(sig)));
+            }
+
+            temp = 0;
+            for t in &self.terms {
+                temp = temp.max(t.temp()?);
+            }
+            if temp >= self.temp_abort {
+                return Err(format!(
+                    "Temperature {}C is too close to critical, {}C is the limit",
+                    temp,
+                    self.temp_abort
+                ));
+            }
+            rpms.push(self.prop.rpm()?.ok_or("fan has no tachometer")?);
+        }
+
+        let last = &rpms[rpms.len() - RPM_SAMPLES..];
+        let rpm = last.iter().sum::<usize>() / RPM_SAMPLES;
+        println!("  PWM {:>3}% -> {:>5} RPM, temp {}C", pwm, rpm, temp);
+        Ok(rpm)
+    }
+
+    fn run(&self) -> Result<Calibration, String> {
+        // Slow down from full speed until fan stops
+        println!("{} slowing down from 100%", self.name);
+        let mut table: Vec<(usize, usize)> = Vec::new();
+        let mut spin_min: Option<usize> = None;
+        for pwm in (0..=100 / STEP).rev().map(|s| s * STEP) {
+            let rpm = self.step(pwm)?;
+            table.push((pwm, rpm));
+            if rpm == 0 {
+                break;
+            }
+            spin_min = Some(pwm);
+        }
+        let spin_min = spin_min.ok_or("fan does not spin even at 100%")?;
+
+        // Speed up stopped fan until it starts
+        let stop = table.last().map(|&(p, _)| p).unwrap_or(0);
+        let start_min = if spin_min == 0 {
+            0
+        } else {
+            println!("{} starting stopped fan", self.name);
+            let mut start = None;
+            for pwm in (stop / STEP + 1..=100 / STEP).map(|s| s * STEP) {
+                if self.step(pwm)? > 0 {
+                    start = Some(pwm);
+                    break;
+                }
+            }
+            start.ok_or("stopped fan does not start even at 100%")?
+        };
+
+        // Fan does not spin below stop point
+        table.extend((0..stop / STEP).map(|s| (s * STEP, 0)));
+        table.sort();
+        Ok(Calibration {
+            spin_min,
+            start_min,
+            rpm: table,
+        })
+    }
+}
+
+/// Refuse to calibrate fan that is driven by daemon listening on control socket,
+/// they would fight for it. Fan released with `karlson release` may be calibrated
+pub fn check_daemon(socket: &Path, dev: &Device) -> Result<(), String> {
+    let resp = match control::request(socket, &json!({"cmd": "status"})) {
+        Ok(r) => r,
+        // No daemon
+        Err(_) => return Ok(()),
+    };
+    let driven = resp["devices"].as_array().into_iter().flatten().any(|d| {
+        let same = match (d["pci"].as_str(), dev.pci.as_ref()) {
+            (Some(p), Some(dp)) => p == dp,
+            _ => d["type"] == dev.dev_type && d["id"] == dev.id,
+        };
+        same && d["hold"] != "released"
+    });
+    if driven {
+        return Err(format!(
+            "karlson daemon controls {}#{}, run `karlson release {}#{}` or stop daemon first",
+            dev.dev_type,
+            dev.id,
+            dev.dev_type,
+            dev.id
+        ));
+    }
+    Ok(())
+}
+
+/// Step PWM of sys device through its range and measure RPM on every step.
+/// Fan control is returned to driver when done.
+/// If calibration is aborted fan is left at 100% under manual control, driver does not get it back
+pub fn calibrate(dev: &Device, set: &Settings, settle: Duration) -> Result<Calibration, String> {
+    if dev.dev_type != "sys" {
+        return Err(String::from("only sys devices with pwm and fan input can be calibrated"));
+    }
+    // Propeller must be able to go down to 0, pwm_min would not let it
+    let free = Settings {
+        pwm_min: 0,
+        pwm_max: 100,
+        ..set.clone()
+    };
+    let d = dsys::sys_device_update(dev, &free);
+    let bench = Bench {
+        name: format!("{}#{} {}", dev.dev_type, dev.id, dev.name),
+        prop: d.propeller.ok_or("device has no PWM file")?,
+        terms: d.termometers,
+        temp_abort: set.temp_crit.saturating_sub(CRIT_MARGIN),
+        settle,
+    };
+    if bench.terms.is_empty() {
+        return Err(String::from("device has no thermometers, it is not safe to stop its fan"));
+    }
+    if bench.prop.rpm()?.is_none() {
+        return Err(String::from("device has no fan input with RPM"));
+    }
+
+    let res = bench.run();
+    match res {
+        Ok(_) => match bench.prop.restore() {
+            Ok(r) => println!("{} fan control restored {}", bench.name, r),
+            Err(e) => println!("ERROR {}", e),
+        },
+        Err(_) => match bench.prop.pwm_leave(100) {
+            Ok(_) => println!("{} fan is left at 100%", bench.name),
+            Err(e) => println!("ERROR {}", e),
+        },
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::read_file_val;
+    use std::env;
+    use std::process;
+
+    fn table() -> Calibration {
+        Calibration {
+            spin_min: 20,
+            start_min: 30,
+            rpm: vec![(0, 0), (15, 0), (20, 600), (50, 1500), (100, 3000)],
+        }
+    }
+
+    #[test]
+    fn rpm_is_interpolated() {
+        let c = table();
+        assert_eq!(c.rpm_at(10), Some(0));
+        assert_eq!(c.rpm_at(35), Some(1050));
+        assert_eq!(c.rpm_at(100), Some(3000));
+        assert_eq!(c.pwm_min(), 35);
+    }
+
+    #[test]
+    fn calibration_is_saved_and_loaded() {
+        let path = env::temp_dir().join(format!("karlson-calibration-{}.json", process::id()));
+        let mut dev = Device::new("sys", 2, "hwmon2(amdgpu)");
+        dev.pci = Some(String::from("0000:03:00.0"));
+        let set = Settings::default();
+
+        save(&path, &dev, &set, &table()).unwrap();
+        let cals = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cals.get("0000:03:00.0/pwm1"), Some(&table()));
+    }
+
+    #[test]
+    fn aborted_calibration_leaves_fan_at_full_speed() {
+        let dir = env::temp_dir().join(format!("karlson-calibrate-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pwm1"), "80").unwrap();
+        fs::write(dir.join("pwm1_enable"), "2").unwrap();
+        fs::write(dir.join("fan1_input"), "1500").unwrap();
+        // Too close to temp_crit 80
+        fs::write(dir.join("temp1_input"), "76000").unwrap();
+        let mut dev = Device::new("sys", 0, "hwmon0(amdgpu)");
+        dev.path = Some(dir.clone());
+
+        let res = calibrate(&dev, &Settings::default(), Duration::from_secs(1));
+        let pwm = read_file_val::<usize>(&dir.join("pwm1"));
+        let enable = read_file_val::<usize>(&dir.join("pwm1_enable"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(res, Err(String::from("Temperature 76C is too close to critical, 75C is the limit")));
+        assert_eq!(pwm, Ok(255));
+        assert_eq!(enable, Ok(1));
+    }
+}
@@ -0,0 +1,82 @@
+// Time source of control loop
+// Monotonic, so NTP clock steps do not break timing of decisions
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+pub trait Clock: Debug + Send {
+    fn now(&self) -> Instant;
+
+    fn box_clone(&self) -> Box<dyn Clock>;
+}
+
+impl Clone for Box<dyn Clock> {
+    fn clone(&self) -> Box<dyn Clock> {
+        self.box_clone()
+    }
+}
+
+/// Real monotonic time
+#[derive(Debug, Clone, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn box_clone(&self) -> Box<dyn Clock> {
+        Box::new((*self).clone())
+    }
+}
+
+/// Time that moves only when told to, for simulation, replay and tests.
+/// Clones share the same time, so clock given to karlson can be moved from outside
+#[derive(Debug, Clone)]
+pub struct FakeClock {
+    start: Instant,
+    /// Milliseconds since start
+    elapsed: Arc<AtomicU64>,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock {
+            start: Instant::now(),
+            elapsed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.elapsed.fetch_add(d.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Set time since clock creation, time never goes back
+    pub fn set(&self, d: Duration) {
+        self.elapsed.fetch_max(d.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.elapsed.load(Ordering::SeqCst))
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> FakeClock {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn box_clone(&self) -> Box<dyn Clock> {
+        Box::new((*self).clone())
+    }
+}
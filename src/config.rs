@@ -0,0 +1,907 @@
+// Configuration file parsing and validation
+// All problems are collected with TOML path of the key, so user can fix them at once
+
+use core::Device;
+use core::Settings;
+use core::pci_slot;
+use core::read_file;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use toml::Value;
+use toml::value::Table;
+
+/// Keys of Settings, allowed at top level and in every [[propellers]]/[[devices]] entry
+const SETTINGS_KEYS: &[&str] = &[
+    "pwm_ok",
+    "pwm_min",
+    "pwm_max",
+    "pwm_step_up",
+    "pwm_step_down",
+    "temp_ok",
+    "temp_hot",
+    "temp_crit",
+    "queue_size",
+    "strategy",
+    "target_temp",
+    "pid_kp",
+    "pid_ki",
+    "pid_kd",
+    "curve",
+    "curve_hysteresis",
+    "pwm_exit",
+    "pwm_fail",
+    "fail_reads",
+    "fail_time",
+    "rpm_max",
+    "stall_time",
+    "zero_rpm_temp",
+    "zero_rpm_hysteresis",
+    "zero_rpm_dwell",
+    "kick_time",
+    "sysfs",
+];
+
+const ROOT_KEYS: &[&str] = &[
+    "idx",
+    "nv_idx",
+    "pci",
+    "propellers",
+    "devices",
+    "socket",
+    "socket_mode",
+    "socket_group",
+    "metrics",
+    "history",
+    "history_format",
+    "history_max_mb",
+    "history_max_hours",
+    "history_keep",
+    "simulate",
+    "calibration",
+];
+
+/// Default control socket path
+pub const SOCKET: &str = "/run/karlson.sock";
+/// Default fan calibration state file
+pub const CALIBRATION: &str = "/var/lib/karlson/calibration.json";
+const PROPELLER_KEYS: &[&str] = &["idx", "pci", "type"];
+const DEVICE_KEYS: &[&str] = &["name", "pwm_file", "sys_temp_input", "nv_temp_input"];
+const SIMULATE_KEYS: &[&str] = &[
+    "load",
+    "ambient",
+    "thermal_mass",
+    "fan_cooling",
+    "passive_cooling",
+    "start_temp",
+];
+
+const STRATEGIES: &[&str] = &["heuristic", "pid", "curve"];
+const HISTORY_FORMATS: &[&str] = &["csv", "json"];
+
+/// Problem with configuration key
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    /// TOML path of the key like `propellers[1].pwm_ok`
+    pub path: String,
+    pub msg: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.msg)
+    }
+}
+
+/// Where and how per-tick samples are recorded
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryConfig {
+    pub path: PathBuf,
+    /// csv or json (newline-delimited)
+    pub format: String,
+    /// File is rotated when it grows over this size in MB
+    pub max_mb: usize,
+    /// File is rotated when it is older than this, 0 disables age rotation
+    pub max_hours: usize,
+    /// Number of rotated files kept as path.1 .. path.N
+    pub keep: usize,
+}
+
+/// Thermal model of simulated device, see sim.rs
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Heat load steps [second from start, W], the first step starts at 0
+    pub load: Vec<(u64, f64)>,
+    /// Ambient temperature in C
+    pub ambient: f64,
+    /// Energy to heat device on 1C in J/C
+    pub thermal_mass: f64,
+    /// Heat removed by fan at 100% per 1C above ambient in W/C
+    pub fan_cooling: f64,
+    /// Heat removed with fan stopped per 1C above ambient in W/C
+    pub passive_cooling: f64,
+    /// Temperature at start, ambient if not set
+    pub start_temp: Option<f64>,
+}
+
+impl Default for SimConfig {
+    /// Something like mid-range GPU under mining load
+    fn default() -> SimConfig {
+        SimConfig {
+            load: vec![(0, 150.0)],
+            ambient: 25.0,
+            thermal_mass: 800.0,
+            fan_cooling: 6.0,
+            passive_cooling: 1.0,
+            start_temp: None,
+        }
+    }
+}
+
+/// Validated configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Top level settings, inherited by all entries
+    pub defaults: Settings,
+    /// Allowed hwmon indexes
+    pub sys_ids: HashSet<i32>,
+    /// Allowed nvidia GPUs by index, UUID or PCI bus id
+    pub nv_ids: HashSet<String>,
+    /// Allowed devices by PCI slot
+    pub pci_ids: HashSet<String>,
+    /// `[[propellers]]` settings for sys devices
+    pub sys_set: HashMap<i32, Settings>,
+    /// `[[propellers]]` settings for nvidia GPUs by index, UUID or PCI bus id
+    pub nv_set: HashMap<String, Settings>,
+    /// `[[propellers]]` settings by PCI slot
+    pub pci_set: HashMap<String, Settings>,
+    /// `[[devices]]` settings
+    pub devices: Vec<Settings>,
+    /// Control socket path, None if disabled
+    pub socket: Option<PathBuf>,
+    /// Control socket file permissions
+    pub socket_mode: u32,
+    /// Group owning control socket
+    pub socket_group: Option<String>,
+    /// Prometheus metrics listen address, None if disabled
+    pub metrics: Option<String>,
+    /// History recording, None if disabled
+    pub history: Option<HistoryConfig>,
+    /// Thermal model for simulate command
+    pub simulate: SimConfig,
+    /// Fan calibration state file, None if disabled
+    pub calibration: Option<PathBuf>,
+}
+
+impl Config {
+    /// Read configuration file, sysfs overrides sysfs key from file
+    pub fn load(path: &Path, sysfs: Option<&str>) -> Result<Config, Vec<ConfigError>> {
+        let file = path.to_string_lossy().to_string();
+        let err = |msg: String| {
+            vec![
+                ConfigError {
+                    path: file.clone(),
+                    msg,
+                },
+            ]
+        };
+
+        let text = read_file(&path.to_path_buf()).map_err(|e| err(e.to_string()))?;
+        let mut cfg = text.parse::<Value>().map_err(|e| err(e.to_string()))?;
+        if let (Some(root), Some(t)) = (sysfs, cfg.as_table_mut()) {
+            t.insert(String::from("sysfs"), Value::from(root));
+        }
+
+        Config::from(&cfg)
+    }
+
+    /// Parse and validate configuration, unknown keys are reported as warnings
+    pub fn from(cfg: &Value) -> Result<Config, Vec<ConfigError>> {
+        let empty = Table::new();
+        let root = match *cfg {
+            Value::Table(ref t) => t,
+            _ => &empty,
+        };
+
+        let mut errs: Vec<ConfigError> = Vec::new();
+        let mut warnings: Vec<ConfigError> = Vec::new();
+
+        unknown_keys(root, "", &[SETTINGS_KEYS, ROOT_KEYS], &mut warnings);
+        let defaults = settings_from(root, "", &Settings::default(), &mut errs);
+        check_settings(&defaults, "", &mut errs);
+
+        let mut conf = Config {
+            sys_ids: HashSet::new(),
+            nv_ids: HashSet::new(),
+            pci_ids: HashSet::new(),
+            sys_set: HashMap::new(),
+            nv_set: HashMap::new(),
+            pci_set: HashMap::new(),
+            devices: Vec::new(),
+            socket: None,
+            socket_mode: 0o600,
+            socket_group: None,
+            metrics: None,
+            history: None,
+            simulate: SimConfig::default(),
+            calibration: None,
+            defaults,
+        };
+
+        let mut r = Reader {
+            t: root,
+            path: "",
+            errs: &mut errs,
+        };
+        conf.sys_ids = r.ids("idx").into_iter().collect();
+        conf.nv_ids = r.keys("nv_idx").into_iter().collect();
+        conf.pci_ids = r.keys("pci").iter().map(|p| pci_slot(p)).collect();
+
+        conf.socket = match r.string("socket") {
+            Some(ref p) if p.is_empty() => None,
+            Some(p) => Some(PathBuf::from(p)),
+            None => Some(PathBuf::from(SOCKET)),
+        };
+        if let Some(mode) = r.string("socket_mode") {
+            match u32::from_str_radix(&mode, 8) {
+                Ok(m) if m <= 0o777 => conf.socket_mode = m,
+                _ => r.error("socket_mode", format!("expected octal mode like \"0660\", found {:?}", mode)),
+            }
+        }
+        conf.socket_group = r.string("socket_group");
+        conf.metrics = r.string("metrics").filter(|m| !m.is_empty());
+        conf.calibration = match r.string("calibration") {
+            Some(ref p) if p.is_empty() => None,
+            Some(p) => Some(PathBuf::from(p)),
+            None => Some(PathBuf::from(CALIBRATION)),
+        };
+
+        let format = r.choice("history_format", HISTORY_FORMATS)
+            .unwrap_or_else(|| String::from("csv"));
+        let max_mb = match r.uint("history_max_mb", 10) {
+            0 => {
+                r.error("history_max_mb", String::from("must be greater than 0"));
+                10
+            }
+            mb => mb,
+        };
+        let max_hours = r.uint("history_max_hours", 24);
+        let keep = r.uint("history_keep", 5);
+        conf.history = r.string("history").filter(|p| !p.is_empty()).map(|p| {
+            HistoryConfig {
+                path: PathBuf::from(p),
+                format,
+                max_mb,
+                max_hours,
+                keep,
+            }
+        });
+
+        conf.simulate = simulate_from(root, &mut errs, &mut warnings);
+
+        // Kind of keys type="nv" entries select GPUs by and the first entry using it
+        let mut nv_kind: Option<(&str, String)> = None;
+        for (i, t) in tables(root, "propellers", &mut errs) {
+            let path = format!("propellers[{}]", i);
+            unknown_keys(t, &path, &[SETTINGS_KEYS, PROPELLER_KEYS], &mut warnings);
+            let set = settings_from(t, &path, &conf.defaults, &mut errs);
+            check_settings(&set, &path, &mut errs);
+
+            let mut r = Reader {
+                t,
+                path: &path,
+                errs: &mut errs,
+            };
+            for pci in r.keys("pci") {
+                let dup = conf.pci_set.insert(pci_slot(&pci), set.clone());
+                if dup.is_some() {
+                    r.error("pci", format!("device {} is configured twice", pci));
+                }
+            }
+
+            if set.dev_type == "nv" {
+                // Nvidia GPUs could be selected by index, UUID or PCI bus id.
+                // One GPU matches keys of each kind, so all entries must use the same kind
+                for key in r.keys("idx") {
+                    let (kind, key) = nv_key(&key);
+                    match nv_kind {
+                        Some((k, ref at)) if k != kind => r.error(
+                            "idx",
+                            format!(
+                                "GPU {} is selected by {}, but {} selects GPUs by {}, the same GPU could match both",
+                                key,
+                                kind,
+                                at,
+                                k
+                            ),
+                        ),
+                        Some(_) => {}
+                        None => nv_kind = Some((kind, path.clone())),
+                    }
+                    if conf.nv_set.insert(key.clone(), set.clone()).is_some() {
+                        r.error("idx", format!("device {} is configured twice", key));
+                    }
+                }
+            } else {
+                for id in r.ids("idx") {
+                    if conf.sys_set.insert(id, set.clone()).is_some() {
+                        r.error("idx", format!("device {} is configured twice", id));
+                    }
+                }
+            }
+        }
+
+        for (i, t) in tables(root, "devices", &mut errs) {
+            let path = format!("devices[{}]", i);
+            unknown_keys(t, &path, &[SETTINGS_KEYS, DEVICE_KEYS], &mut warnings);
+            let set = settings_from(t, &path, &conf.defaults, &mut errs);
+            check_settings(&set, &path, &mut errs);
+
+            if !t.contains_key("sys_temp_input") && !t.contains_key("nv_temp_input") {
+                errs.push(ConfigError {
+                    path,
+                    msg: String::from("sys_temp_input or nv_temp_input is required"),
+                });
+                continue;
+            }
+            conf.devices.push(set);
+        }
+
+        for w in &warnings {
+            println!("WARNING {}", w);
+        }
+
+        if !errs.is_empty() {
+            return Err(errs);
+        }
+
+        Ok(conf)
+    }
+
+    /// Check if device is selected by idx, nv_idx or pci
+    pub fn allows(&self, d: &Device) -> bool {
+        if d.pci.as_ref().is_some_and(|p| self.pci_ids.contains(p)) {
+            return true;
+        }
+        match d.dev_type.as_ref() {
+            "nv" => self.nv_ids.iter().any(|k| d.matches(k)),
+            _ => self.sys_ids.contains(&d.id),
+        }
+    }
+
+    /// Keys of all `[[propellers]]` entries selecting device.
+    /// The same device may be given by `pci` and by `idx`, only one of them is allowed
+    pub fn selectors(&self, d: &Device) -> Vec<String> {
+        let mut res: Vec<String> = Vec::new();
+        if let Some(ref p) = d.pci {
+            if self.pci_set.contains_key(p) {
+                res.push(format!("pci {}", p));
+            }
+        }
+        match d.dev_type.as_ref() {
+            "nv" => {
+                let mut keys: Vec<&String> = self.nv_set.keys().filter(|k| d.matches(k)).collect();
+                keys.sort();
+                res.extend(keys.iter().map(|k| format!("idx {}", k)));
+            }
+            _ => {
+                if self.sys_set.contains_key(&d.id) {
+                    res.push(format!("idx {}", d.id));
+                }
+            }
+        }
+        res
+    }
+
+    /// Settings for device, `[[propellers]]` entry selected by PCI slot has priority
+    pub fn settings_for(&self, d: &Device) -> &Settings {
+        let pci_ns = d.pci.as_ref().and_then(|p| self.pci_set.get(p));
+        match d.dev_type.as_ref() {
+            "nv" => {
+                // Only one key could match, index, UUID and bus id are not mixed
+                pci_ns
+                    .or_else(|| {
+                        self.nv_set.iter().find(|&(k, _)| d.matches(k)).map(|(_, s)| s)
+                    })
+                    .unwrap_or(&self.defaults)
+            }
+            _ => pci_ns
+                .or_else(|| self.sys_set.get(&d.id))
+                .unwrap_or(&self.defaults),
+        }
+    }
+}
+
+fn key_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Kind of Nvidia GPU key and the key in form it is stored
+fn nv_key(key: &str) -> (&'static str, String) {
+    if key.chars().all(|c| c.is_ascii_digit()) {
+        ("index", String::from(key))
+    } else if key.contains(':') {
+        ("bus id", pci_slot(key))
+    } else {
+        ("UUID", key.to_uppercase())
+    }
+}
+
+/// Typed access to table keys, wrong values are reported and default is used instead
+struct Reader<'a> {
+    t: &'a Table,
+    path: &'a str,
+    errs: &'a mut Vec<ConfigError>,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&mut self, key: &str, msg: String) {
+        self.errs.push(ConfigError {
+            path: key_path(self.path, key),
+            msg,
+        });
+    }
+
+    fn expected(&mut self, key: &str, what: &str, v: &Value) {
+        let msg = format!("expected {}, found {} {}", what, v.type_str(), v);
+        self.error(key, msg);
+    }
+
+    /// Non negative integer not greater than max
+    fn uint_max(&mut self, key: &str, max: usize) -> Option<usize> {
+        match self.t.get(key) {
+            None => None,
+            Some(&Value::Integer(i)) if i >= 0 && i as usize <= max => Some(i as usize),
+            Some(v) => {
+                let what = if max == usize::MAX {
+                    String::from("non negative integer")
+                } else {
+                    format!("integer 0..{}", max)
+                };
+                self.expected(key, &what, v);
+                None
+            }
+        }
+    }
+
+    fn uint(&mut self, key: &str, default: usize) -> usize {
+        self.uint_max(key, usize::MAX).unwrap_or(default)
+    }
+
+    /// Fan speed in percents
+    fn pwm(&mut self, key: &str, default: usize) -> usize {
+        self.uint_max(key, 100).unwrap_or(default)
+    }
+
+    /// Float, integers are accepted too
+    fn float(&mut self, key: &str, default: f64) -> f64 {
+        match self.t.get(key) {
+            None => default,
+            Some(&Value::Float(f)) => f,
+            Some(&Value::Integer(i)) => i as f64,
+            Some(v) => {
+                self.expected(key, "number", v);
+                default
+            }
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        match self.t.get(key) {
+            None => None,
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(v) => {
+                self.expected(key, "string", v);
+                None
+            }
+        }
+    }
+
+    /// One of allowed strings
+    fn choice(&mut self, key: &str, allowed: &[&str]) -> Option<String> {
+        let s = self.string(key)?;
+        if allowed.contains(&s.as_str()) {
+            return Some(s);
+        }
+        self.error(key, format!("expected one of {:?}, found {:?}", allowed, s));
+        None
+    }
+
+    fn array(&mut self, key: &str) -> Option<&'a Vec<Value>> {
+        match self.t.get(key) {
+            None => None,
+            Some(Value::Array(a)) => Some(a),
+            Some(v) => {
+                self.expected(key, "array", v);
+                None
+            }
+        }
+    }
+
+    /// Array of hwmon indexes, duplicates are reported
+    fn ids(&mut self, key: &str) -> Vec<i32> {
+        let mut res: Vec<i32> = Vec::new();
+        for v in self.array(key).map(|a| a.as_slice()).unwrap_or(&[]) {
+            match *v {
+                Value::Integer(i) if i >= 0 && i <= i64::from(i32::MAX) => {
+                    if res.contains(&(i as i32)) {
+                        self.error(key, format!("duplicate device id {}", i));
+                    }
+                    res.push(i as i32);
+                }
+                _ => self.expected(key, "array of non negative integers", v),
+            }
+        }
+        res
+    }
+
+    /// String keys, could be single value or array.
+    /// Integer values are converted to strings, duplicates are reported
+    fn keys(&mut self, key: &str) -> Vec<String> {
+        let values = match self.t.get(key) {
+            None => return Vec::new(),
+            Some(Value::Array(a)) => a.as_slice(),
+            Some(v) => ::std::slice::from_ref(v),
+        };
+
+        let mut res: Vec<String> = Vec::new();
+        for v in values {
+            let k = match *v {
+                Value::String(ref s) => s.clone(),
+                Value::Integer(i) if i >= 0 => i.to_string(),
+                _ => {
+                    self.expected(key, "string or non negative integer", v);
+                    continue;
+                }
+            };
+            if res.contains(&k) {
+                self.error(key, format!("duplicate device id {}", k));
+            }
+            res.push(k);
+        }
+        res
+    }
+
+    /// Array of [temperature, pwm] points.
+    /// Temperatures must strictly grow and fan speed must not go down
+    fn curve(&mut self, key: &str) -> Option<Vec<(usize, usize)>> {
+        let mut res: Vec<(usize, usize)> = Vec::new();
+        for p in self.array(key)? {
+            let pt = match p.as_array().map(|a| a.as_slice()) {
+                Some(&[Value::Integer(t), Value::Integer(pwm)])
+                    if t >= 0 && (0..=100).contains(&pwm) => (t as usize, pwm as usize),
+                _ => {
+                    self.expected(key, "[temp, pwm 0..100] point", p);
+                    return None;
+                }
+            };
+            if let Some(&(t, pwm)) = res.last() {
+                if pt.0 <= t || pt.1 < pwm {
+                    let msg = format!(
+                        "points must be monotonic, [{}, {}] after [{}, {}]",
+                        pt.0,
+                        pt.1,
+                        t,
+                        pwm
+                    );
+                    self.error(key, msg);
+                    return None;
+                }
+            }
+            res.push(pt);
+        }
+        if res.is_empty() {
+            self.error(key, String::from("curve has no points"));
+            return None;
+        }
+
+        Some(res)
+    }
+
+    /// Heat load in W, constant or [[second, W], ...] steps with growing seconds
+    fn load(&mut self, key: &str) -> Option<Vec<(u64, f64)>> {
+        let steps = match self.t.get(key) {
+            None => return None,
+            Some(Value::Array(a)) => a,
+            Some(_) => {
+                let w = self.float(key, -1.0);
+                if w < 0.0 {
+                    self.error(key, String::from("heat load can not be negative"));
+                    return None;
+                }
+                return Some(vec![(0, w)]);
+            }
+        };
+
+        let mut res: Vec<(u64, f64)> = Vec::new();
+        for p in steps {
+            let step = match p.as_array().map(|a| a.as_slice()) {
+                Some(&[Value::Integer(t), Value::Integer(w)]) if t >= 0 && w >= 0 => (t as u64, w as f64),
+                Some(&[Value::Integer(t), Value::Float(w)]) if t >= 0 && w >= 0.0 => (t as u64, w),
+                _ => {
+                    self.expected(key, "[second, W] step", p);
+                    return None;
+                }
+            };
+            let first = res.is_empty() && step.0 != 0;
+            if first || res.last().is_some_and(|&(t, _)| step.0 <= t) {
+                self.error(key, String::from("steps must start at 0 and seconds must grow"));
+                return None;
+            }
+            res.push(step);
+        }
+        if res.is_empty() {
+            self.error(key, String::from("load has no steps"));
+            return None;
+        }
+
+        Some(res)
+    }
+}
+
+/// Tables from array of tables like [[devices]] with their indexes
+fn tables<'a>(root: &'a Table, key: &str, errs: &mut Vec<ConfigError>) -> Vec<(usize, &'a Table)> {
+    let arr = match root.get(key) {
+        None => return Vec::new(),
+        Some(Value::Array(a)) => a,
+        Some(v) => {
+            errs.push(ConfigError {
+                path: String::from(key),
+                msg: format!("expected [[{}]] blocks, found {}", key, v.type_str()),
+            });
+            return Vec::new();
+        }
+    };
+
+    let mut res = Vec::new();
+    for (i, v) in arr.iter().enumerate() {
+        match *v {
+            Value::Table(ref t) => res.push((i, t)),
+            _ => {
+                errs.push(ConfigError {
+                    path: format!("{}[{}]", key, i),
+                    msg: format!("expected table, found {}", v.type_str()),
+                })
+            }
+        }
+    }
+    res
+}
+
+fn unknown_keys(t: &Table, path: &str, allowed: &[&[&str]], warnings: &mut Vec<ConfigError>) {
+    for k in t.keys() {
+        if !allowed.iter().any(|keys| keys.contains(&k.as_str())) {
+            warnings.push(ConfigError {
+                path: key_path(path, k),
+                msg: String::from("unknown key is ignored"),
+            });
+        }
+    }
+}
+
+/// Settings from table, values that are not set are taken from parent
+fn settings_from(t: &Table, path: &str, s: &Settings, errs: &mut Vec<ConfigError>) -> Settings {
+    let mut r = Reader { t, path, errs };
+
+    Settings {
+        name: r.string("name").or_else(|| s.name.clone()),
+        dev_type: r.choice("type", &["sys", "nv"]).unwrap_or_else(|| String::from("sys")),
+        pwm_ok: r.pwm("pwm_ok", s.pwm_ok),
+        pwm_min: r.pwm("pwm_min", s.pwm_min),
+        pwm_max: r.pwm("pwm_max", s.pwm_max),
+        pwm_step_up: r.pwm("pwm_step_up", s.pwm_step_up as usize) as isize,
+        pwm_step_down: r.pwm("pwm_step_down", s.pwm_step_down as usize) as isize,
+        temp_ok: r.uint("temp_ok", s.temp_ok),
+        temp_hot: r.uint("temp_hot", s.temp_hot),
+        temp_crit: r.uint("temp_crit", s.temp_crit),
+        queue_size: r.uint("queue_size", s.queue_size),
+        strategy: r.choice("strategy", STRATEGIES).unwrap_or_else(|| s.strategy.clone()),
+        target_temp: r.uint_max("target_temp", usize::MAX).or(s.target_temp),
+        pid_kp: r.float("pid_kp", s.pid_kp),
+        pid_ki: r.float("pid_ki", s.pid_ki),
+        pid_kd: r.float("pid_kd", s.pid_kd),
+        curve: r.curve("curve").unwrap_or_else(|| s.curve.clone()),
+        curve_hysteresis: r.uint("curve_hysteresis", s.curve_hysteresis),
+        pwm_exit: r.uint_max("pwm_exit", 100).or(s.pwm_exit),
+        pwm_fail: r.pwm("pwm_fail", s.pwm_fail),
+        fail_reads: r.uint("fail_reads", s.fail_reads),
+        fail_time: r.uint("fail_time", s.fail_time as usize) as u64,
+        rpm_max: r.uint_max("rpm_max", usize::MAX).or(s.rpm_max),
+        stall_time: r.uint("stall_time", s.stall_time as usize) as u64,
+        zero_rpm_temp: r.uint_max("zero_rpm_temp", usize::MAX).or(s.zero_rpm_temp),
+        zero_rpm_hysteresis: r.uint("zero_rpm_hysteresis", s.zero_rpm_hysteresis),
+        zero_rpm_dwell: r.uint("zero_rpm_dwell", s.zero_rpm_dwell as usize) as u64,
+        kick_time: r.uint("kick_time", s.kick_time as usize) as u64,
+        sys_pwm_file: r.string("pwm_file")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| s.sys_pwm_file.clone()),
+        sys_temp_files: match r.array("sys_temp_input") {
+            Some(a) => {
+                a.iter()
+                    .filter_map(|v| match *v {
+                        Value::String(ref p) => Some(PathBuf::from(p)),
+                        _ => {
+                            r.expected("sys_temp_input", "array of paths", v);
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            None => s.sys_temp_files.clone(),
+        },
+        sys_root: r.string("sysfs")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| s.sys_root.clone()),
+        nv_temp_ids: if t.contains_key("nv_temp_input") {
+            r.keys("nv_temp_input")
+        } else {
+            s.nv_temp_ids.clone()
+        },
+    }
+}
+
+/// Thermal model from [simulate] table, defaults if there is no such table
+fn simulate_from(root: &Table, errs: &mut Vec<ConfigError>, warnings: &mut Vec<ConfigError>) -> SimConfig {
+    let d = SimConfig::default();
+    let t = match root.get("simulate") {
+        None => return d,
+        Some(Value::Table(t)) => t,
+        Some(v) => {
+            errs.push(ConfigError {
+                path: String::from("simulate"),
+                msg: format!("expected [simulate] table, found {}", v.type_str()),
+            });
+            return d;
+        }
+    };
+    unknown_keys(t, "simulate", &[SIMULATE_KEYS], warnings);
+    let mut r = Reader {
+        t,
+        path: "simulate",
+        errs,
+    };
+
+    let sc = SimConfig {
+        load: r.load("load").unwrap_or_else(|| d.load.clone()),
+        ambient: r.float("ambient", d.ambient),
+        thermal_mass: r.float("thermal_mass", d.thermal_mass),
+        fan_cooling: r.float("fan_cooling", d.fan_cooling),
+        passive_cooling: r.float("passive_cooling", d.passive_cooling),
+        start_temp: t.get("start_temp").map(|_| r.float("start_temp", d.ambient)),
+    };
+    if sc.thermal_mass <= 0.0 {
+        r.error("thermal_mass", String::from("must be greater than 0"));
+    }
+    if sc.fan_cooling < 0.0 || sc.passive_cooling < 0.0 {
+        r.error("fan_cooling", String::from("cooling coefficients can not be negative"));
+    }
+    if sc.passive_cooling + sc.fan_cooling <= 0.0 {
+        r.error("passive_cooling", String::from("device has no cooling at all"));
+    }
+    sc
+}
+
+/// Check relations between values
+fn check_settings(s: &Settings, path: &str, errs: &mut Vec<ConfigError>) {
+    let mut err = |msg: String| {
+        errs.push(ConfigError {
+            path: if path.is_empty() {
+                String::from("settings")
+            } else {
+                String::from(path)
+            },
+            msg,
+        })
+    };
+
+    if s.temp_ok >= s.temp_hot || s.temp_hot >= s.temp_crit {
+        err(format!(
+            "temp_ok {} < temp_hot {} < temp_crit {} expected",
+            s.temp_ok,
+            s.temp_hot,
+            s.temp_crit
+        ));
+    }
+    if s.pwm_min > s.pwm_ok || s.pwm_ok > s.pwm_max {
+        err(format!(
+            "pwm_min {} <= pwm_ok {} <= pwm_max {} expected",
+            s.pwm_min,
+            s.pwm_ok,
+            s.pwm_max
+        ));
+    }
+    if let Some(t) = s.zero_rpm_temp {
+        if t + s.zero_rpm_hysteresis >= s.temp_hot {
+            err(format!(
+                "zero_rpm_temp {} + zero_rpm_hysteresis {} < temp_hot {} expected",
+                t,
+                s.zero_rpm_hysteresis,
+                s.temp_hot
+            ));
+        }
+    }
+    if s.strategy == "curve" && s.curve.is_empty() {
+        err(String::from("curve strategy requires curve points"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> Vec<String> {
+        let cfg = text.parse::<Value>().unwrap();
+        Config::from(&cfg).unwrap_err().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn curve_points_are_validated() {
+        let errs = errors("strategy = \"curve\"\ncurve = [[40, 30], [30, 50]]\n");
+        assert_eq!(
+            errs,
+            vec![
+                "curve: points must be monotonic, [30, 50] after [40, 30]",
+                "settings: curve strategy requires curve points",
+            ]
+        );
+
+        let errs = errors("[[propellers]]\nidx = [1]\ncurve = [[40, 130]]\n");
+        assert_eq!(errs, vec!["propellers[0].curve: expected [temp, pwm 0..100] point, found array [40, 130]"]);
+    }
+
+    #[test]
+    fn errors_have_toml_paths() {
+        let errs = errors("pwm_ok = \"60\"\n[[propellers]]\nidx = [1]\npwm_ok = 120\ntemp_hot = 90\n");
+        assert_eq!(
+            errs,
+            vec![
+                "pwm_ok: expected integer 0..100, found string \"60\"",
+                "propellers[0].pwm_ok: expected integer 0..100, found integer 120",
+                "propellers[0]: temp_ok 65 < temp_hot 90 < temp_crit 80 expected",
+            ]
+        );
+    }
+
+    #[test]
+    fn gpu_is_selected_by_one_kind_of_key() {
+        let errs = errors(
+            "[[propellers]]\ntype = \"nv\"\nidx = [\"0\"]\n\
+             [[propellers]]\ntype = \"nv\"\nidx = [\"GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49\"]\n",
+        );
+        assert_eq!(
+            errs,
+            vec![
+                "propellers[1].idx: GPU GPU-09B509F0-961C-189E-BF2E-A1FD2D999B49 is selected by UUID, \
+                 but propellers[0] selects GPUs by index, the same GPU could match both",
+            ]
+        );
+
+        let errs = errors(
+            "[[propellers]]\ntype = \"nv\"\nidx = [\"00000000:01:00.0\", \"0000:01:00.0\"]\n",
+        );
+        assert_eq!(errs, vec!["propellers[0].idx: device 0000:01:00.0 is configured twice"]);
+    }
+
+    #[test]
+    fn gpu_gets_settings_of_its_entry() {
+        let cfg = "[[propellers]]\ntype = \"nv\"\npwm_ok = 70\n\
+                   idx = [\"GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49\"]\n"
+            .parse::<Value>()
+            .unwrap();
+        let conf = Config::from(&cfg).unwrap();
+
+        let mut gpu = Device::new("nv", 0, "GeForce GTX 1070");
+        gpu.uuid = Some(String::from("GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49"));
+        assert_eq!(conf.settings_for(&gpu).pwm_ok, 70);
+        gpu.uuid = Some(String::from("GPU-51e5ba3b-3f42-d70b-075b-06b36565f091"));
+        assert_eq!(conf.settings_for(&gpu).pwm_ok, 60);
+    }
+}
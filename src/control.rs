@@ -0,0 +1,372 @@
+// Control socket
+// Unix socket with one JSON request and one JSON response per connection line.
+// Clients are read and answered from own thread, requests are executed by daemon loop.
+// Access is controlled by socket file permissions
+
+use core::pci_slot;
+use karlson::Hold;
+use karlson::Karlson;
+
+use libc;
+use serde_json;
+use serde_json::Value;
+
+use std::ffi::CString;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+/// Client has this much time to send request and read response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longer requests are not read till the end
+const MAX_REQUEST: u64 = 8192;
+/// Daemon loop takes requests between ticks, client is answered with error if it does not
+const REPLY_TIMEOUT: Duration = Duration::from_secs(8);
+const STOPPED: &str = "daemon loop is stopped";
+
+/// Fan speed is forced for 10 minutes if time is not provided
+const FORCE_SEC: u64 = 600;
+
+/// Request read by socket thread, daemon loop sends response back
+#[derive(Debug)]
+struct Request {
+    req: Value,
+    resp: Sender<Value>,
+}
+
+/// Control socket served from own thread, so slow or stuck client would not stop control loop
+#[derive(Debug)]
+pub struct Control {
+    requests: Receiver<Request>,
+    path: PathBuf,
+}
+
+impl Control {
+    /// Create socket with provided mode and group.
+    /// Stale socket file left by killed daemon is replaced
+    pub fn bind(path: &Path, mode: u32, group: Option<&str>) -> Result<Control, String> {
+        let pstr = path.to_string_lossy();
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(format!("{} exists and it is not socket", pstr));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is used by another running daemon", pstr));
+            }
+            fs::remove_file(path).map_err(|e| format!("Can not remove {} {}", pstr, e))?;
+        }
+
+        let listener = UnixListener::bind(path).map_err(|e| format!("Can not bind {} {}", pstr, e))?;
+        let (tx, requests) = mpsc::channel();
+        let control = Control {
+            requests,
+            path: path.to_path_buf(),
+        };
+
+        if let Some(g) = group {
+            socket_group(path, g)?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Can not set mode {:o} on {} {}", mode, pstr, e))?;
+        thread::spawn(move || serve(&listener, &tx));
+
+        Ok(control)
+    }
+
+    /// Execute requests received since last call without waiting for new ones.
+    /// Return true if rescan of devices was requested
+    pub fn serve(&self, karlsons: &mut [Karlson], devices: &mut [Karlson]) -> bool {
+        let mut rescan = false;
+        while let Ok(r) = self.requests.try_recv() {
+            let resp = handle(&r.req, karlsons, devices, &mut rescan).unwrap_or_else(|e| {
+                json!({"ok": false, "error": e})
+            });
+            // Client could give up waiting already
+            let _ = r.resp.send(resp);
+        }
+        rescan
+    }
+}
+
+/// Send request to running daemon and return its response.
+/// Error if daemon is not reachable or it refused the request
+pub fn request(path: &Path, req: &Value) -> Result<Value, String> {
+    let pstr = path.to_string_lossy();
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("Can not connect to {} {}, is daemon running?", pstr, e))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT * 5)).map_err(|e| e.to_string())?;
+
+    let mut w = &stream;
+    writeln!(w, "{}", req).map_err(|e| format!("Can not send request to {} {}", pstr, e))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("No response from {} {}", pstr, e))?;
+    let resp: Value = serde_json::from_str(&line).map_err(|e| format!("Invalid response {}", e))?;
+
+    if resp["ok"].as_bool() != Some(true) {
+        return Err(resp["error"].as_str().unwrap_or("Request failed").to_string());
+    }
+    Ok(resp)
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn socket_group(path: &Path, group: &str) -> Result<(), String> {
+    let cgroup = CString::new(group).map_err(|e| e.to_string())?;
+    let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    unsafe {
+        let gr = libc::getgrnam(cgroup.as_ptr());
+        if gr.is_null() {
+            return Err(format!("Unknown socket group {}", group));
+        }
+        if libc::chown(cpath.as_ptr(), libc::uid_t::MAX, (*gr).gr_gid) != 0 {
+            return Err(format!(
+                "Can not change group of {} to {}",
+                path.to_string_lossy(),
+                group
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Answer clients one by one, thread ends on first request after Control is dropped
+fn serve(listener: &UnixListener, requests: &Sender<Request>) {
+    for stream in listener.incoming() {
+        let res = stream
+            .map_err(|e| e.to_string())
+            .and_then(|s| serve_client(&s, requests));
+        match res {
+            Err(ref e) if e == STOPPED => return,
+            Err(e) => println!("ERROR control socket client {}", e),
+            Ok(()) => (),
+        }
+    }
+}
+
+fn serve_client(stream: &UnixStream, requests: &Sender<Request>) -> Result<(), String> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_REQUEST))
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+
+    let resp = match serde_json::from_str::<Value>(&line) {
+        Ok(req) => {
+            let (tx, rx) = mpsc::channel();
+            requests
+                .send(Request { req, resp: tx })
+                .map_err(|_| String::from(STOPPED))?;
+            rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|_| {
+                json!({"ok": false, "error": "Daemon is busy, try again"})
+            })
+        }
+        Err(e) => json!({"ok": false, "error": format!("Invalid JSON {}", e)}),
+    };
+
+    let mut w = stream;
+    writeln!(w, "{}", resp).map_err(|e| e.to_string())
+}
+
+/// Execute request
+///  {"cmd": "status"}
+///  {"cmd": "pause", "dev": "sys#0"}
+///  {"cmd": "resume", "dev": "nv#1"}
+///  {"cmd": "set", "dev": "GPU-09b5...", "pwm": 80, "sec": 600}
+///  {"cmd": "release", "dev": "0000:03:00.0"}
+///  {"cmd": "rescan"}
+fn handle(
+    req: &Value,
+    karlsons: &mut [Karlson],
+    devices: &mut [Karlson],
+    rescan: &mut bool,
+) -> Result<Value, String> {
+    let cmd = req["cmd"].as_str().ok_or("cmd is required")?;
+    match cmd {
+        "status" => {
+            let devs: Vec<Value> = karlsons.iter().chain(devices.iter()).map(karlson_json).collect();
+            return Ok(json!({"ok": true, "devices": devs}));
+        }
+        "rescan" => {
+            *rescan = true;
+            return Ok(json!({"ok": true}));
+        }
+        "pause" | "resume" | "set" | "release" => (),
+        _ => return Err(format!("Unknown command {}", cmd)),
+    }
+
+    let key = req["dev"].as_str().ok_or("dev is required")?;
+    let k = find(karlsons, devices, key)?;
+    match cmd {
+        "pause" => k.pause(),
+        "resume" => k.resume(),
+        "release" => k.release_control(),
+        _ => {
+            let pwm = req["pwm"]
+                .as_u64()
+                .filter(|p| *p <= 100)
+                .ok_or("pwm 0..100 is required")?;
+            let sec = match req.get("sec") {
+                None => FORCE_SEC,
+                Some(s) => s.as_u64().ok_or("sec must be non negative integer")?,
+            };
+            k.force(pwm as usize, Duration::from_secs(sec))?;
+        }
+    }
+
+    Ok(json!({"ok": true, "device": karlson_json(k)}))
+}
+
+/// Find device by type#id like sys#0, nv#1, dev#0 or by UUID and PCI slot
+fn find<'a>(
+    karlsons: &'a mut [Karlson],
+    devices: &'a mut [Karlson],
+    key: &str,
+) -> Result<&'a mut Karlson, String> {
+    let mut found: Vec<&'a mut Karlson> = karlsons
+        .iter_mut()
+        .chain(devices.iter_mut())
+        .filter(|k| if key.contains('#') {
+            format!("{}#{}", k.dev.dev_type, k.dev.id) == key
+        } else {
+            k.dev.uuid.as_ref().is_some_and(|u| u.eq_ignore_ascii_case(key)) ||
+                k.dev.pci.as_ref().is_some_and(|p| *p == pci_slot(key))
+        })
+        .collect();
+
+    match found.len() {
+        0 => Err(format!("Device {} is not found", key)),
+        1 => Ok(found.remove(0)),
+        _ => Err(format!("Device {} is ambiguous, use type#id", key)),
+    }
+}
+
+fn karlson_json(k: &Karlson) -> Value {
+    let (hold, forced_sec) = match k.hold {
+        Hold::Auto => ("auto", None),
+        Hold::Paused => ("paused", None),
+        Hold::Released => ("released", None),
+        Hold::Forced(_, until) => (
+            "forced",
+            Some(until.saturating_duration_since(k.now()).as_secs()),
+        ),
+    };
+
+    json!({
+        "type": k.dev.dev_type,
+        "id": k.dev.id,
+        "name": k.dev.name,
+        "pci": k.dev.pci,
+        "uuid": k.dev.uuid,
+        "temps": k.temps,
+        "pwm": k.pwm_speed,
+        "reason": k.reason,
+        "strategy": k.settings().strategy,
+        "pwm_min": k.jam.pwm_min,
+        "pwm_ok": k.jam.pwm_ok,
+        "pwm_max": k.jam.pwm_max,
+        "temp_ok": k.jam.temp_ok,
+        "temp_hot": k.jam.temp_hot,
+        "temp_crit": k.jam.temp_crit,
+        "sensor_fault": k.sensor_fault,
+        "rpm": k.rpm,
+        "stalled": k.stalled,
+        "fan_off": k.fan_off,
+        "hold": hold,
+        "forced_sec": forced_sec,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use karlson::KarlsonBuilder;
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    fn socket(name: &str) -> Control {
+        let path = env::temp_dir().join(format!("karlson-{}-{}.sock", name, process::id()));
+        Control::bind(&path, 0o600, None).unwrap()
+    }
+
+    /// Device without hardware, sys type would be looked up in real sysfs
+    fn fan() -> Karlson {
+        KarlsonBuilder::new("test", 0, "fan").build()
+    }
+
+    /// Send request from other thread while daemon loop is emulated here
+    fn ask(c: &Control, karlsons: &mut [Karlson], req: Value) -> Result<Value, String> {
+        let path = c.path.clone();
+        let client = thread::spawn(move || request(&path, &req));
+        while !client.is_finished() {
+            c.serve(karlsons, &mut []);
+            thread::sleep(Duration::from_millis(10));
+        }
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn requests_are_executed_by_daemon_loop() {
+        let c = socket("requests");
+        let mut ks = vec![fan()];
+
+        let resp = ask(&c, &mut ks, json!({"cmd": "pause", "dev": "test#0"})).unwrap();
+        assert_eq!(resp["device"]["hold"], "paused");
+        assert_eq!(ks[0].hold, Hold::Paused);
+
+        let resp = ask(&c, &mut ks, json!({"cmd": "status"})).unwrap();
+        assert_eq!(resp["devices"][0]["name"], "fan");
+        assert_eq!(resp["devices"][0]["hold"], "paused");
+
+        let err = ask(&c, &mut ks, json!({"cmd": "pause", "dev": "test#1"}));
+        assert_eq!(err, Err(String::from("Device test#1 is not found")));
+        let err = ask(&c, &mut ks, json!({"cmd": "set", "dev": "test#0", "pwm": 120}));
+        assert_eq!(err, Err(String::from("pwm 0..100 is required")));
+        let err = ask(&c, &mut ks, json!({"cmd": "reboot"}));
+        assert_eq!(err, Err(String::from("Unknown command reboot")));
+    }
+
+    #[test]
+    fn stuck_client_does_not_stop_daemon_loop() {
+        let c = socket("stuck");
+        let mut ks = vec![fan()];
+
+        // Client that never sends its request
+        let _stuck = UnixStream::connect(&c.path).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        c.serve(&mut ks, &mut []);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Invalid request is answered by socket thread itself after stuck client times out
+        let stream = UnixStream::connect(&c.path).unwrap();
+        let mut w = &stream;
+        writeln!(w, "status").unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("{\"error\":\"Invalid JSON"));
+    }
+}
@@ -1,14 +1,19 @@
-use std::error::Error as eError;
 use std::fmt::Debug;
+use std::fmt::Display;
 use std::fs::File;
+use std::fs::OpenOptions;
 use std::io::Error;
 use std::io::Read;
+use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
-extern crate toml;
-use toml::Value;
-use toml::value::Table;
 
 pub fn read_file(p: &PathBuf) -> Result<String, Error> {
     match File::open(p) {
@@ -34,23 +39,96 @@ where
                 Err(_) => Err(format!("Can not parse value {:?}", v)),
             }
         }
-        Err(e) => Err(String::from(e.description())),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-pub fn read_file_val_or<N>(p: &PathBuf, def: N) -> N
+/// Check that file could be opened for reading and, if asked, for writing.
+/// Nothing is written to file
+pub fn check_file(p: &PathBuf, write: bool) -> Result<(), String> {
+    if !p.is_file() {
+        return Err(format!("{} does not exist", p.to_string_lossy()));
+    }
+    read_file(p).map_err(|e| format!("Can not read {} {}", p.to_string_lossy(), e))?;
+    if write {
+        OpenOptions::new()
+            .write(true)
+            .open(p)
+            .map_err(|e| format!("Can not open {} for writing {}", p.to_string_lossy(), e))?;
+    }
+    Ok(())
+}
+
+/// Write value to existing file, like sysfs attribute
+pub fn write_file_val<N>(p: &PathBuf, val: N) -> Result<(), String>
 where
-    N: FromStr,
+    N: Display,
 {
-    match read_file(p) {
-        Ok(v) => {
-            match v.trim().parse::<N>() {
-                Ok(v) => v,
-                Err(_) => def,
+    let mut fopts = OpenOptions::new();
+    fopts.write(true);
+
+    match fopts.open(p) {
+        Ok(mut f) => write!(f, "{}", val).map_err(|e| {
+            format!("Can not write {} to {} {}", val, p.to_string_lossy(), e)
+        }),
+        Err(e) => Err(format!(
+            "Can not write {} to {} {}",
+            val,
+            p.to_string_lossy(),
+            e
+        )),
+    }
+}
+
+/// Run external command and wait for its output.
+/// Command is killed if it does not exit in provided time
+pub fn run_cmd(cmd: &mut Command, timeout: Duration) -> Result<Output, String> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let mut child = cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Can not execute {} {}", name, e))?;
+
+    // Read pipes in background, so child would not block on full pipe
+    let mut pout = child.stdout.take().unwrap();
+    let mut perr = child.stderr.take().unwrap();
+    let tout = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pout.read_to_end(&mut buf);
+        buf
+    });
+    let terr = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = perr.read_to_end(&mut buf);
+        buf
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(st)) => break st,
+            Ok(None) => {
+                if start.elapsed() > timeout {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!(
+                        "{} killed, no response in {} sec",
+                        name,
+                        timeout.as_secs()
+                    ));
+                }
+                thread::sleep(Duration::from_millis(20));
             }
+            Err(e) => return Err(format!("Can not wait for {} {}", name, e)),
         }
-        Err(_) => def,
-    }
+    };
+
+    Ok(Output {
+        status,
+        stdout: tout.join().unwrap_or_default(),
+        stderr: terr.join().unwrap_or_default(),
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -58,11 +136,84 @@ pub struct Device {
     pub id: i32,
     pub dev_type: String,
     pub name: String,
-    pub propeller: Option<Box<Propeller>>,
-    pub termometers: Vec<Box<Thermometer>>,
+    /// Device directory like /sys/class/hwmon/hwmon0
+    pub path: Option<PathBuf>,
+    /// PCI slot like 0000:03:00.0, unlike id it does not change after reboot
+    pub pci: Option<String>,
+    /// GPU UUID for Nvidia devices
+    pub uuid: Option<String>,
+    /// Driver name like amdgpu
+    pub driver: Option<String>,
+    /// DRM card like card0
+    pub card: Option<String>,
+    pub propeller: Option<Box<dyn Propeller>>,
+    pub termometers: Vec<Box<dyn Thermometer>>,
 }
 
-#[derive(Debug, Clone)]
+/// Normalize PCI slot to 0000:03:00.0 format,
+/// nvidia-smi reports it with 8 digits domain like 00000000:03:00.0
+pub fn pci_slot(bus: &str) -> String {
+    let lbus = bus.trim().to_lowercase();
+    match lbus.find(':') {
+        Some(i) if i > 4 && lbus[..i - 4].chars().all(|c| c == '0') => String::from(&lbus[i - 4..]),
+        _ => lbus,
+    }
+}
+
+impl Device {
+    /// Device without hardware, propeller and thermometers are added later
+    pub fn new(dev_type: &str, id: i32, name: &str) -> Device {
+        Device {
+            id,
+            dev_type: String::from(dev_type),
+            name: String::from(name),
+            path: None,
+            pci: None,
+            uuid: None,
+            driver: None,
+            card: None,
+            propeller: None,
+            termometers: Vec::new(),
+        }
+    }
+
+    /// Check if device could be selected by provided key.
+    /// Key is device index, UUID or PCI slot
+    pub fn matches(&self, key: &str) -> bool {
+        key == self.id.to_string() ||
+            self.uuid.as_ref().is_some_and(|u| u.eq_ignore_ascii_case(key)) ||
+            self.pci.as_ref().is_some_and(|p| *p == pci_slot(key))
+    }
+
+    /// Check if both describe the same hardware
+    pub fn same(&self, other: &Device) -> bool {
+        self.dev_type == other.dev_type &&
+            match self.uuid {
+                Some(ref u) => other.uuid.as_ref() == Some(u),
+                None => self.id == other.id && self.path == other.path,
+            }
+    }
+
+    /// Stable device identity, as much as we know about it
+    pub fn ident(&self) -> String {
+        let mut res: Vec<String> = Vec::new();
+        if let Some(ref pci) = self.pci {
+            res.push(format!("pci={}", pci));
+        }
+        if let Some(ref uuid) = self.uuid {
+            res.push(format!("uuid={}", uuid));
+        }
+        if let Some(ref drv) = self.driver {
+            res.push(format!("driver={}", drv));
+        }
+        if let Some(ref card) = self.card {
+            res.push(format!("card={}", card));
+        }
+        res.join(" ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Settings {
     pub name: Option<String>,
     /// Device type
@@ -74,6 +225,8 @@ pub struct Settings {
     pub pwm_min: usize,
     /// Normal fan value in percents
     pub pwm_ok: usize,
+    /// Maximum fan value strategy may choose, critical temperature still sets 100%
+    pub pwm_max: usize,
     // Absolute value for MAX fan
     //pwm_max_abs: usize,
     /// Fan step in %
@@ -84,137 +237,145 @@ pub struct Settings {
     pub temp_hot: usize,
     pub temp_crit: usize,
     pub queue_size: usize,
+    /// Fan control strategy
+    ///  - heuristic
+    ///  - pid
+    ///  - curve
+    pub strategy: String,
+    /// Temperature PID strategy holds, temp_ok if not set
+    pub target_temp: Option<usize>,
+    /// PID gains, % per C, % per C*sec and % per C/sec
+    pub pid_kp: f64,
+    pub pid_ki: f64,
+    pub pid_kd: f64,
+    /// Fan curve points (temperature C, fan %), both growing
+    pub curve: Vec<(usize, usize)>,
+    /// Temperature should drop on this many C below curve point to slow fan down
+    pub curve_hysteresis: usize,
+    /// Fan speed to set on exit, if not set fan control returned to driver
+    pub pwm_exit: Option<usize>,
+    /// Fail-safe fan speed when temperature can not be read
+    pub pwm_fail: usize,
+    /// Number of consecutive failed temperature reads to trigger fail-safe
+    pub fail_reads: usize,
+    /// Seconds without temperature data to trigger fail-safe
+    pub fail_time: u64,
+    /// Fan RPM at 100%, fan spinning much slower than expected is reported as stalled
+    pub rpm_max: Option<usize>,
+    /// Seconds fan may not spin as commanded before it is reported as stalled, 0 disables it
+    pub stall_time: u64,
+    /// Fan is stopped below this temperature, None disables zero RPM mode
+    pub zero_rpm_temp: Option<usize>,
+    /// Stopped fan starts again at zero_rpm_temp + hysteresis
+    pub zero_rpm_hysteresis: usize,
+    /// Seconds temperature has to stay below zero_rpm_temp before fan is stopped
+    pub zero_rpm_dwell: u64,
+    /// Seconds of full speed given to fan starting from 0, 0 disables it
+    pub kick_time: u64,
 
     // Nvidia settings //
-    pub nv_temp_ids: Vec<usize>,
+    /// GPU indexes, UUIDs or PCI bus ids
+    pub nv_temp_ids: Vec<String>,
 
     // Sys devices settings //
     pub sys_temp_files: Vec<PathBuf>,
     // Sys PWM file name in folder
     pub sys_pwm_file: PathBuf,
+    /// Where sysfs is mounted, /sys by default
+    pub sys_root: PathBuf,
 }
 
-impl Settings {
-    pub fn from(cfg: &Value) -> Settings {
-        Settings::from_with(
-            cfg,
-            &Settings {
-                name: None,
-                //pwm_max_abs: 255,
-                dev_type: String::from("sys"),
-                pwm_ok: 60,
-                pwm_min: 21,
-                pwm_step_up: 2,
-                pwm_step_down: 1,
-                temp_ok: 65,
-                temp_hot: 75,
-                temp_crit: 80,
-                queue_size: 15,
-                sys_pwm_file: PathBuf::from("pwm1"),
-                sys_temp_files: vec![PathBuf::from("temp1_input")],
-                nv_temp_ids: Vec::new(),
-            },
-        )
-    }
-
-    pub fn from_with(cfg: &Value, s: &Settings) -> Settings {
-        let vt = "stub_key='stub_val'".parse::<Value>().unwrap();
-        let t: &Table = cfg.as_table().unwrap_or(vt.as_table().unwrap());
-
-        let sstr = String::from("sys");
-
+impl Default for Settings {
+    fn default() -> Settings {
         Settings {
-            name: t.get("name")
-                .map(|v| v.as_str().map(|s| String::from(s)))
-                .or(Some(s.name.clone()))
-                .unwrap_or_default(),
-            dev_type: t.get("type")
-                .map(|v| v.as_str().map(|s| String::from(s)))
-                .and_then(|v| v)
-                .unwrap_or(sstr),
-            //pwm_max: t.get("pwm_max").unwrap_or(&Value::from(s.pwm_max as i64)).as_integer().unwrap() as usize,
-            pwm_ok: t.get("pwm_ok")
-                .unwrap_or(&Value::from(s.pwm_ok as i64))
-                .as_integer()
-                .unwrap() as usize,
-            pwm_min: t.get("pwm_min")
-                .unwrap_or(&Value::from(s.pwm_min as i64))
-                .as_integer()
-                .unwrap() as usize,
+            name: None,
+            //pwm_max_abs: 255,
+            dev_type: String::from("sys"),
+            pwm_ok: 60,
+            pwm_min: 21,
+            pwm_max: 100,
             pwm_step_up: 5,
             pwm_step_down: 2,
-            temp_ok: t.get("temp_ok")
-                .unwrap_or(&Value::from(s.temp_ok as i64))
-                .as_integer()
-                .unwrap() as usize,
-            temp_hot: t.get("temp_hot")
-                .unwrap_or(&Value::from(s.temp_hot as i64))
-                .as_integer()
-                .unwrap() as usize,
-            temp_crit: t.get("temp_crit")
-                .unwrap_or(&Value::from(s.temp_crit as i64))
-                .as_integer()
-                .unwrap() as usize,
-            queue_size: t.get("queue_size")
-                .unwrap_or(&Value::from(s.queue_size as i64))
-                .as_integer()
-                .unwrap() as usize,
-            sys_pwm_file: if t.contains_key("pwm_file") && t["pwm_file"].is_str() {
-                PathBuf::from(t["pwm_file"].as_str().unwrap())
-            } else {
-                s.sys_pwm_file.clone()
-            },
-            sys_temp_files: if t.contains_key("sys_temp_input") {
-                t["sys_temp_input"]
-                    .as_array()
-                    .unwrap_or(&Vec::<Value>::new())
-                    .iter()
-                    .filter_map(|v| v.as_str())
-                    .map(|v| PathBuf::from(v))
-                    .collect::<Vec<PathBuf>>()
-            } else {
-                s.sys_temp_files.clone()
-            },
-            nv_temp_ids: if t.contains_key("nv_temp_input") {
-                t["nv_temp_input"]
-                    .as_array()
-                    .unwrap_or(&Vec::<Value>::new())
-                    .iter()
-                    .filter_map(|v| v.as_integer())
-                    .map(|i| i as usize)
-                    .collect::<Vec<usize>>()
-            } else {
-                s.nv_temp_ids.clone()
-            },
+            temp_ok: 65,
+            temp_hot: 75,
+            temp_crit: 80,
+            queue_size: 15,
+            strategy: String::from("heuristic"),
+            target_temp: None,
+            pid_kp: 3.0,
+            pid_ki: 0.02,
+            pid_kd: 10.0,
+            curve: Vec::new(),
+            curve_hysteresis: 3,
+            pwm_exit: None,
+            pwm_fail: 100,
+            fail_reads: 3,
+            fail_time: 60,
+            rpm_max: None,
+            stall_time: 30,
+            zero_rpm_temp: None,
+            zero_rpm_hysteresis: 5,
+            zero_rpm_dwell: 120,
+            kick_time: 2,
+            sys_pwm_file: PathBuf::from("pwm1"),
+            sys_temp_files: vec![PathBuf::from("temp1_input")],
+            sys_root: PathBuf::from("/sys"),
+            nv_temp_ids: Vec::new(),
         }
     }
 }
 
-
 pub trait Propeller: Debug {
     fn pwm(&self) -> Result<usize, String>;
 
     fn pwm_set(&self, val: usize) -> Result<usize, String>;
 
-    fn box_clone(&self) -> Box<Propeller>;
+    fn box_clone(&self) -> Box<dyn Propeller>;
 
     fn configure(&mut self, set: &Settings);
+
+    /// Return fan control back to driver/firmware.
+    /// Result contains human readable description of restored state
+    fn restore(&self) -> Result<String, String>;
+
+    /// Set fan speed and keep it under manual control after karlson stops
+    fn pwm_leave(&self, val: usize) -> Result<usize, String>;
+
+    /// Fan speed from tachometer, None if fan does not report it
+    fn rpm(&self) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
 }
 
-impl Clone for Box<Propeller> {
-    fn clone(&self) -> Box<Propeller> {
+impl Clone for Box<dyn Propeller> {
+    fn clone(&self) -> Box<dyn Propeller> {
         self.box_clone()
     }
 }
 
 pub trait Thermometer: Debug {
-    fn temp(&self) -> usize;
+    /// Temperature in C or error if sensor does not report it
+    fn temp(&self) -> Result<usize, String>;
 
-    fn box_clone(&self) -> Box<Thermometer>;
+    fn box_clone(&self) -> Box<dyn Thermometer>;
 }
 
-impl Clone for Box<Thermometer> {
-    fn clone(&self) -> Box<Thermometer> {
+impl Clone for Box<dyn Thermometer> {
+    fn clone(&self) -> Box<dyn Thermometer> {
         self.box_clone()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pci_slot_is_normalized() {
+        assert_eq!(pci_slot("00000000:01:00.0"), "0000:01:00.0");
+        assert_eq!(pci_slot("0000:03:00.0"), "0000:03:00.0");
+        assert_eq!(pci_slot(" 00000000:0A:00.0\n"), "0000:0a:00.0");
+        assert_eq!(pci_slot("00000001:01:00.0"), "0001:01:00.0");
+        assert_eq!(pci_slot("01:00.0"), "01:00.0");
+    }
+}
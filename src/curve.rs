@@ -0,0 +1,129 @@
+// Fan curve strategy
+// Fan speed is linearly interpolated between [temperature, pwm] points
+
+use core::Settings;
+use karlson::Controller;
+use karlson::Decision;
+use karlson::Input;
+
+#[derive(Debug, Clone)]
+pub struct Curve {
+    points: Vec<(usize, usize)>,
+    hysteresis: usize,
+}
+
+impl Curve {
+    pub fn new(s: &Settings) -> Curve {
+        Curve {
+            points: s.curve.clone(),
+            hysteresis: s.curve_hysteresis,
+        }
+    }
+
+    /// Fan speed for temperature, below first point and above last one
+    /// the speed of the nearest point is used
+    fn pwm_at(&self, temp: usize) -> usize {
+        let mut prev: Option<(usize, usize)> = None;
+        for &(t, pwm) in &self.points {
+            if temp <= t {
+                return match prev {
+                    None => pwm,
+                    Some((pt, ppwm)) => ppwm + (pwm - ppwm) * (temp - pt) / (t - pt),
+                };
+            }
+            prev = Some((t, pwm));
+        }
+
+        prev.map(|(_, pwm)| pwm).unwrap_or(100)
+    }
+}
+
+impl Controller for Curve {
+    fn decide(&mut self, inp: &Input) -> Decision {
+        let up = self.pwm_at(inp.temp);
+        // On the way down fan slows only when temperature dropped on hysteresis
+        let down = self.pwm_at(inp.temp + self.hysteresis);
+
+        let (pwm, reason) = if up >= inp.pwm {
+            (up, "curve")
+        } else if down < inp.pwm {
+            (down, "curve with hysteresis")
+        } else {
+            (inp.pwm, "curve hysteresis, hold")
+        };
+
+        Decision {
+            pwm: pwm.min(inp.jam.pwm_max),
+            reason: String::from(reason),
+        }
+    }
+
+    fn configure(&mut self, set: &Settings) {
+        if !set.curve.is_empty() {
+            self.points = set.curve.clone();
+        }
+        self.hysteresis = set.curve_hysteresis;
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new((*self).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use karlson::Jam;
+    use std::collections::VecDeque;
+
+    fn curve() -> Curve {
+        Curve::new(&Settings {
+            curve: vec![(40, 30), (60, 50), (80, 100)],
+            curve_hysteresis: 3,
+            ..Settings::default()
+        })
+    }
+
+    fn decide(c: &mut Curve, temp: usize, pwm: usize) -> Decision {
+        let jam = Jam {
+            pwm_ok: 60,
+            pwm_min: 21,
+            pwm_max: 90,
+            temp_ok: 65,
+            temp_hot: 75,
+            temp_crit: 80,
+        };
+        let tlog = VecDeque::new();
+        c.decide(&Input {
+            temp,
+            tlog: &tlog,
+            tlog_max: temp,
+            tlog_size: 0,
+            pwm,
+            idle: 0,
+            dt: 0.0,
+            jam: &jam,
+        })
+    }
+
+    #[test]
+    fn speed_is_interpolated_between_points() {
+        let c = curve();
+        assert_eq!(c.pwm_at(30), 30);
+        assert_eq!(c.pwm_at(40), 30);
+        assert_eq!(c.pwm_at(50), 40);
+        assert_eq!(c.pwm_at(70), 75);
+        assert_eq!(c.pwm_at(95), 100);
+    }
+
+    #[test]
+    fn fan_slows_down_after_hysteresis() {
+        let mut c = curve();
+        assert_eq!(decide(&mut c, 69, 75).pwm, 75);
+        assert_eq!(decide(&mut c, 69, 75).reason, "curve hysteresis, hold");
+        assert_eq!(decide(&mut c, 65, 75).pwm, 70);
+        assert_eq!(decide(&mut c, 71, 75).pwm, 77);
+        // Curve goes to 100%, but pwm_max is the limit
+        assert_eq!(decide(&mut c, 85, 75).pwm, 90);
+    }
+}
@@ -0,0 +1,580 @@
+// Daemon loop
+// Devices allowed by configuration are spun every tick, configuration is reloaded on SIGHUP
+// and checked against hardware by `karlson check`
+
+use calibrate;
+use config::Config;
+use control::Control;
+use core::Device;
+use core::Settings;
+use core::check_file;
+use core::read_file_val;
+use dnv;
+use dsys;
+use history::History;
+use karlson::Karlson;
+use karlson::list_devices;
+use metrics::Metrics;
+use signal;
+
+use libc;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// One line per device with temperature, fan speed and faults
+pub fn format_info(devs: &[Karlson]) -> String {
+    let d: usize = 0;
+    let forms: Vec<String> = devs.iter()
+        .map(|it| {
+            format!(
+                "  {}#{} {}C {}%{}{}{}{} :: {}",
+                it.dev.id,
+                it.dev.dev_type,
+                it.dev
+                    .termometers
+                    .iter()
+                    .filter_map(|t| t.temp().ok())
+                    .max()
+                    .unwrap_or(d),
+                it.pwm_speed,
+                it.rpm.map_or(String::new(), |r| format!(" {}rpm", r)),
+                if it.sensor_fault { " SENSOR FAULT" } else { "" },
+                if it.stalled { " FAN STALLED" } else { "" },
+                if it.fan_off { " FAN OFF" } else { "" },
+                it.dev.name,
+            )
+        })
+        .collect();
+
+    forms.join("\n")
+}
+
+fn init_karlsons(conf: &Config) -> Vec<Karlson> {
+    let (sys_ids, nv_ids, pci_ids) = (&conf.sys_ids, &conf.nv_ids, &conf.pci_ids);
+
+    let mut karlsons: Vec<Karlson> = Vec::new();
+    let devs = list_devices(&conf.defaults.sys_root);
+
+    #[cfg(debug_assertions)]
+    {
+        println!("PROPELLERS {:?}", devs);
+    }
+
+    for d in devs {
+        if !conf.allows(&d) {
+            #[cfg(debug_assertions)]
+            {
+                println!("Skip device #{} {}", d.id, d.name);
+            }
+            continue;
+        }
+
+        karlsons.push(Karlson::new(&d, conf.settings_for(&d)));
+    }
+
+    if karlsons.is_empty() {
+        if !sys_ids.is_empty() {
+            println!("Allowed system devices ids {:?}", sys_ids);
+        }
+        if !conf.nv_set.is_empty() {
+            println!("Allowed Nvidia devices ids {:?}", nv_ids);
+        }
+        if !pci_ids.is_empty() {
+            println!("Allowed PCI slots {:?}", pci_ids);
+        }
+    }
+
+    karlsons
+}
+
+fn init_devices(conf: &Config) -> Vec<Karlson> {
+    if conf.devices.is_empty() {
+        println!("[[devices]] configuration is empty");
+    }
+
+    let devices: Vec<Karlson> = conf.devices
+        .iter()
+        .enumerate()
+        .map(|(id, s)| Karlson::new_device(id as i32, s))
+        .collect();
+
+    #[cfg(debug_assertions)]
+    {
+        println!("DEVICES {:?}", devices);
+    }
+    devices
+}
+
+
+/// Why daemon woke up before next tick
+enum Wake {
+    Signal(i32),
+    Rescan,
+}
+
+/// Wait for next tick serving control socket requests.
+/// Return earlier if signal was received or rescan was requested
+fn sleep_tick(
+    halfs: usize,
+    control: Option<&Control>,
+    karlsons: &mut [Karlson],
+    devices: &mut [Karlson],
+) -> Option<Wake> {
+    for _ in 0..halfs {
+        if let Some(sig) = signal::take() {
+            return Some(Wake::Signal(sig));
+        }
+        if control.is_some_and(|c| c.serve(karlsons, devices)) {
+            return Some(Wake::Rescan);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+    signal::take().map(Wake::Signal)
+}
+
+fn shutdown(karlsons: &mut [Karlson], devices: &mut [Karlson], sig: i32) {
+    println!("Received {}, shutting down", signal::name(sig));
+    for k in karlsons.iter_mut().chain(devices.iter_mut()) {
+        k.release();
+    }
+    // Nvidia fans are changed in background, they must be done before exit
+    dnv::nv_flush(dnv::SETTINGS_TIMEOUT);
+}
+
+/// Apply new settings to running devices.
+/// Devices that are not allowed anymore are released, new allowed devices are added
+fn reload_karlsons(conf: &Config, karlsons: &mut Vec<Karlson>) {
+    let mut kept: Vec<Karlson> = Vec::new();
+    for mut k in karlsons.drain(..) {
+        if !conf.allows(&k.dev) {
+            println!("{}#{} removed -> {}", k.dev.dev_type, k.dev.id, k.dev.name);
+            k.release();
+            continue;
+        }
+
+        let s = conf.settings_for(&k.dev);
+        if k.inputs_changed(s) {
+            println!("{}#{} inputs changed, recreated -> {}", k.dev.dev_type, k.dev.id, k.dev.name);
+            // pwm_exit would keep manual mode, and new karlson would take it for driver mode
+            k.restore();
+            kept.push(Karlson::new(&k.dev, s));
+        } else {
+            if k.configure(s) {
+                println!("{}#{} settings updated -> {}", k.dev.dev_type, k.dev.id, k.dev.name);
+            }
+            kept.push(k);
+        }
+    }
+
+    for d in list_devices(&conf.defaults.sys_root) {
+        if conf.allows(&d) && !kept.iter().any(|k| k.dev.same(&d)) {
+            println!("{}#{} added -> {}", d.dev_type, d.id, d.name);
+            kept.push(Karlson::new(&d, conf.settings_for(&d)));
+        }
+    }
+
+    *karlsons = kept;
+}
+
+/// Apply new [[devices]] settings, devices are matched by their position in config
+fn reload_devices(conf: &Config, devices: &mut Vec<Karlson>) {
+    for k in devices.iter_mut().skip(conf.devices.len()) {
+        println!("{}#{} removed -> {}", k.dev.dev_type, k.dev.id, k.dev.name);
+        k.release();
+    }
+    devices.truncate(conf.devices.len());
+
+    for (id, s) in conf.devices.iter().enumerate() {
+        match devices.get_mut(id) {
+            Some(k) if k.inputs_changed(s) => {
+                println!("{}#{} inputs changed, recreated -> {}", k.dev.dev_type, id, k.dev.name);
+                k.restore();
+                *k = Karlson::new_device(id as i32, s);
+            }
+            Some(k) => {
+                if k.configure(s) {
+                    println!("{}#{} settings updated -> {}", k.dev.dev_type, id, k.dev.name);
+                }
+            }
+            None => {
+                let k = Karlson::new_device(id as i32, s);
+                println!("{}#{} added -> {}", k.dev.dev_type, id, k.dev.name);
+                devices.push(k);
+            }
+        }
+    }
+}
+
+/// Reload configuration file, running configuration is kept if new one is invalid
+fn reload(
+    conf_path: &Path,
+    sysfs: Option<&str>,
+    conf: &mut Config,
+    karlsons: &mut Vec<Karlson>,
+    devices: &mut Vec<Karlson>,
+) {
+    println!("Reloading configuration {}", conf_path.to_string_lossy());
+    match Config::load(conf_path, sysfs) {
+        Ok(c) => {
+            if c.socket != conf.socket || c.socket_mode != conf.socket_mode ||
+                c.socket_group != conf.socket_group || c.metrics != conf.metrics
+            {
+                println!("WARNING control socket and metrics settings are applied after restart");
+            }
+            reload_karlsons(&c, karlsons);
+            reload_devices(&c, devices);
+            *conf = c;
+        }
+        Err(errs) => {
+            for e in &errs {
+                println!("ERROR {}", e);
+            }
+            println!("ERROR configuration is not reloaded, keep running with previous one");
+        }
+    }
+}
+
+/// Give sys fans their measured PWM to RPM tables from calibration state file
+fn load_calibration(conf: &Config, karlsons: &mut [Karlson]) {
+    let cals = match conf.calibration.as_ref().map(|p| calibrate::load(p)) {
+        Some(Ok(c)) => c,
+        Some(Err(e)) => {
+            println!("ERROR calibration is not loaded {}", e);
+            return;
+        }
+        None => Default::default(),
+    };
+    for k in karlsons.iter_mut().filter(|k| k.dev.dev_type == "sys") {
+        let cal = cals.get(&calibrate::key(&k.dev, k.settings())).cloned();
+        if cal.is_some() && cal != k.calibration {
+            println!("{}#{} calibration loaded -> {}", k.dev.dev_type, k.dev.id, k.dev.name);
+        }
+        k.calibration = cal;
+    }
+}
+
+fn open_history(conf: &Config) -> Option<History> {
+    conf.history.as_ref().and_then(|c| match History::open(c) {
+        Ok(h) => Some(h),
+        Err(e) => {
+            println!("ERROR history is disabled {}", e);
+            None
+        }
+    })
+}
+
+fn loop_daemon(
+    conf_path: &Path,
+    sysfs: Option<&str>,
+    mut conf: Config,
+    control: Option<Control>,
+    metrics: Option<Metrics>,
+    mut karlsons: Vec<Karlson>,
+    mut devices: Vec<Karlson>,
+) {
+    let mut history = open_history(&conf);
+    let mut t = Instant::now();
+    let mut start = true;
+    loop {
+        let halfs = if karlsons.is_empty() && devices.is_empty() {
+            println!("(X_X) No devices was added to service. Just do nothing and sleep!");
+            20
+        } else {
+            for d in &mut devices {
+                d.spin();
+            }
+
+            for k in &mut karlsons {
+                k.spin();
+            }
+            if let Some(ref mut h) = history {
+                h.record(&karlsons, &devices);
+            }
+            10
+        };
+        if let Some(ref m) = metrics {
+            m.update(&karlsons, &devices);
+        }
+
+        match sleep_tick(halfs, control.as_ref(), &mut karlsons, &mut devices) {
+            Some(Wake::Signal(libc::SIGHUP)) => {
+                reload(conf_path, sysfs, &mut conf, &mut karlsons, &mut devices);
+                load_calibration(&conf, &mut karlsons);
+                if history.as_ref().map(|h| h.config()) != conf.history.as_ref() {
+                    history = open_history(&conf);
+                }
+                continue;
+            }
+            Some(Wake::Signal(sig)) => return shutdown(&mut karlsons, &mut devices, sig),
+            Some(Wake::Rescan) => {
+                println!("Rescan devices");
+                reload_karlsons(&conf, &mut karlsons);
+                load_calibration(&conf, &mut karlsons);
+                continue;
+            }
+            None => (),
+        }
+
+        let n = Instant::now();
+        let d = n.duration_since(t).as_secs();
+
+        if d > 120 || start {
+            start = false;
+            t = n;
+            if !karlsons.is_empty() {
+                println!("PROPELLERS:\n{}", format_info(&karlsons));
+            }
+            if !devices.is_empty() {
+                println!("DEVICES:\n{}", format_info(&devices));
+            }
+        }
+    }
+}
+
+/// Control devices allowed by configuration until SIGTERM or SIGINT.
+/// SIGHUP reloads configuration file, control socket and metrics are served if configured
+pub fn run(conf_path: &Path, sysfs: Option<&str>, conf: Config) {
+    signal::listen();
+
+    let control = conf.socket.as_ref().and_then(|p| {
+        match Control::bind(p, conf.socket_mode, conf.socket_group.as_deref()) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                println!("ERROR control socket is disabled {}", e);
+                None
+            }
+        }
+    });
+
+    let metrics = conf.metrics.as_ref().and_then(|addr| match Metrics::bind(addr) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            println!("ERROR metrics are disabled {}", e);
+            None
+        }
+    });
+
+    let mut karlsons = init_karlsons(&conf);
+    load_calibration(&conf, &mut karlsons);
+    let devices = init_devices(&conf);
+
+    loop_daemon(conf_path, sysfs, conf, control, metrics, karlsons, devices);
+}
+
+/// Effective settings of device for check output
+pub fn format_settings(s: &Settings) -> String {
+    let mut res = format!(
+        "  strategy {}, pwm min {} ok {} max {}, temp ok {} hot {} crit {}\n",
+        s.strategy,
+        s.pwm_min,
+        s.pwm_ok,
+        s.pwm_max,
+        s.temp_ok,
+        s.temp_hot,
+        s.temp_crit
+    );
+    match s.strategy.as_ref() {
+        "pid" => res.push_str(&format!(
+            "  pid target {}C kp {} ki {} kd {}\n",
+            s.target_temp.unwrap_or(s.temp_ok),
+            s.pid_kp,
+            s.pid_ki,
+            s.pid_kd
+        )),
+        "curve" => res.push_str(&format!(
+            "  curve {:?} hysteresis {}C\n",
+            s.curve,
+            s.curve_hysteresis
+        )),
+        _ => res.push_str(&format!(
+            "  pwm step up {} down {}, queue {}\n",
+            s.pwm_step_up,
+            s.pwm_step_down,
+            s.queue_size
+        )),
+    }
+    res.push_str(&format!(
+        "  fail-safe pwm {} after {} reads or {} sec, on exit {}",
+        s.pwm_fail,
+        s.fail_reads,
+        s.fail_time,
+        s.pwm_exit.map_or(String::from("restore driver control"), |p| format!("pwm {}", p))
+    ));
+    if s.stall_time > 0 {
+        res.push_str(&format!(
+            "\n  stall after {} sec{}",
+            s.stall_time,
+            s.rpm_max.map_or(String::new(), |r| format!(", {} RPM at 100%", r))
+        ));
+    }
+    if let Some(t) = s.zero_rpm_temp {
+        res.push_str(&format!(
+            "\n  zero rpm below {}C after {} sec, start above {}C with {} sec kick",
+            t,
+            s.zero_rpm_dwell,
+            t + s.zero_rpm_hysteresis,
+            s.kick_time
+        ));
+    }
+    res
+}
+
+/// Print check result and return number of problems
+fn check_report(what: &str, res: Result<(), String>) -> usize {
+    match res {
+        Ok(_) => {
+            println!("  OK {}", what);
+            0
+        }
+        Err(e) => {
+            println!("  ERROR {} {}", what, e);
+            1
+        }
+    }
+}
+
+/// Check that sys PWM file is writable and temperature files are readable
+fn check_sys_files(pwm: &Path, temps: &[PathBuf]) -> usize {
+    let pwm = pwm.to_path_buf();
+    let mut problems = check_report("pwm", check_file(&pwm, true));
+
+    let mut name = pwm.file_name().unwrap_or_default().to_os_string();
+    name.push("_enable");
+    let enable = pwm.with_file_name(name);
+    if enable.exists() {
+        problems += check_report("pwm enable", check_file(&enable, true));
+    }
+
+    if temps.is_empty() {
+        problems += check_report("temperature", Err(String::from("no inputs")));
+    }
+    for t in temps {
+        problems += check_report(
+            "temperature",
+            read_file_val::<usize>(t)
+                .map(|_| ())
+                .map_err(|e| format!("{} {}", t.to_string_lossy(), e)),
+        );
+    }
+    problems
+}
+
+/// Check allowed device against hardware without changing anything on it
+fn check_device(d: &Device, s: &Settings) -> usize {
+    println!("{}#{} {} {}", d.dev_type, d.id, d.name, d.ident());
+    println!("{}", format_settings(s));
+
+    match d.dev_type.as_ref() {
+        "nv" => {
+            let mut problems = check_report(
+                "fan",
+                d.propeller.as_ref().map_or(Err(String::from("no fan")), |p| p.pwm().map(|_| ())),
+            );
+            for t in &d.termometers {
+                problems += check_report("temperature", t.temp().map(|_| ()));
+            }
+            problems
+        }
+        _ => {
+            let dir = match d.path {
+                Some(ref p) => p.clone(),
+                None => dsys::sys_dir_devices(&s.sys_root).join(format!("hwmon{}", d.id)),
+            };
+            let temps: Vec<PathBuf> = s.sys_temp_files.iter().map(|t| dir.join(t)).collect();
+            check_sys_files(&dir.join(&s.sys_pwm_file), &temps)
+        }
+    }
+}
+
+/// Resolve configuration against hardware, return number of problems.
+/// Nothing is written to the hardware
+pub fn check(conf: &Config) -> usize {
+    let devs = list_devices(&conf.defaults.sys_root);
+    let mut problems = 0;
+    let mut missing = |what: String| {
+        println!("ERROR {} is not found", what);
+        problems += 1;
+    };
+
+    let is_sys = |d: &&Device| d.dev_type != "nv";
+    let is_nv = |d: &&Device| d.dev_type == "nv";
+    for id in &conf.sys_ids {
+        if !devs.iter().filter(is_sys).any(|d| d.id == *id) {
+            missing(format!("idx hwmon{}", id));
+        }
+    }
+    for id in conf.sys_set.keys() {
+        if !devs.iter().filter(is_sys).any(|d| d.id == *id) {
+            missing(format!("[[propellers]] idx hwmon{}", id));
+        }
+    }
+    for k in &conf.nv_ids {
+        if !devs.iter().filter(is_nv).any(|d| d.matches(k)) {
+            missing(format!("nv_idx GPU {}", k));
+        }
+    }
+    for k in conf.nv_set.keys() {
+        if !devs.iter().filter(is_nv).any(|d| d.matches(k)) {
+            missing(format!("[[propellers]] idx GPU {}", k));
+        }
+    }
+    for p in &conf.pci_ids {
+        if !devs.iter().any(|d| d.pci.as_ref() == Some(p)) {
+            missing(format!("pci {}", p));
+        }
+    }
+    for p in conf.pci_set.keys() {
+        if !devs.iter().any(|d| d.pci.as_ref() == Some(p)) {
+            missing(format!("[[propellers]] pci {}", p));
+        }
+    }
+
+    for d in &devs {
+        let sel = conf.selectors(d);
+        if sel.len() > 1 {
+            println!(
+                "ERROR {}#{} {} is configured twice in [[propellers]] by {}",
+                d.dev_type,
+                d.id,
+                d.name,
+                sel.join(", ")
+            );
+            problems += 1;
+        }
+    }
+
+    for d in devs.iter().filter(|d| !conf.allows(d)) {
+        if !std::ptr::eq(conf.settings_for(d), &conf.defaults) {
+            println!(
+                "WARNING {}#{} {} has [[propellers]] settings, but it is not allowed by idx, nv_idx or pci",
+                d.dev_type,
+                d.id,
+                d.name
+            );
+        }
+    }
+
+    for d in devs.iter().filter(|d| conf.allows(d)) {
+        problems += check_device(d, conf.settings_for(d));
+    }
+
+    for (id, s) in conf.devices.iter().enumerate() {
+        println!("dev#{} {}", id, s.name.clone().unwrap_or_default());
+        println!("{}", format_settings(s));
+
+        let temps: Vec<PathBuf> = s.sys_temp_files
+            .iter()
+            .map(|t| dsys::sys_path(&s.sys_root, t))
+            .collect();
+        problems += check_sys_files(&dsys::sys_path(&s.sys_root, &s.sys_pwm_file), &temps);
+        for k in &s.nv_temp_ids {
+            problems += check_report(
+                &format!("GPU {} temperature", k),
+                dnv::nv_termometer_from(k).and_then(|t| t.temp()).map(|_| ()),
+            );
+        }
+    }
+
+    problems
+}
@@ -4,189 +4,653 @@ use core::Device;
 use core::Propeller;
 use core::Thermometer;
 use core::Settings;
+use core::pci_slot;
 
+use core::run_cmd;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 use regex::Regex;
 
+/// How long nvidia-smi sample is used by all GPUs before it is taken again.
+/// Should be less than daemon tick, so every tick has single nvidia-smi call
+static SAMPLE_TTL: Duration = Duration::from_secs(2);
+/// How long readers wait for running nvidia-smi, after that last sample is used
+static SAMPLE_WAIT: Duration = Duration::from_secs(2);
+/// Older samples are not used at all
+static SAMPLE_MAX_AGE: Duration = Duration::from_secs(20);
+/// nvidia-smi is killed if it does not respond in this time
+static SMI_TIMEOUT: Duration = Duration::from_secs(10);
+/// nvidia-settings is killed if it does not respond in this time
+pub static SETTINGS_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long restore waits for GPU writer, after that fan control is restored in background
+static RESTORE_WAIT: Duration = Duration::from_secs(2);
+
+/// Sample of all GPUs shared by Nvidia thermometers and propellers.
+/// nvidia-smi runs in background thread, so hanging GPU would not stop control loop
+static SAMPLER: Mutex<NvSampler<HashMap<String, NvStat>>> = Mutex::new(NvSampler {
+    sample: None,
+    started: None,
+});
+static SAMPLER_READY: Condvar = Condvar::new();
+
+/// Fans and their RPM from nvidia-settings, taken in background next to nvidia-smi sample.
+/// Readers never wait for it, last sample is used
+static FAN_SAMPLER: Mutex<NvSampler<NvFans>> = Mutex::new(NvSampler {
+    sample: None,
+    started: None,
+});
+static FAN_SAMPLER_READY: Condvar = Condvar::new();
+
+/// Fans of GPUs by UUID in upper case, as nvidia-settings lists them
+static FANS: Mutex<BTreeMap<String, Vec<usize>>> = Mutex::new(BTreeMap::new());
+
+/// Fan writers of GPUs by UUID, every GPU has own thread running nvidia-settings
+static WRITERS: Mutex<BTreeMap<String, Arc<NvWriter>>> = Mutex::new(BTreeMap::new());
+
+/// GPU state from nvidia-smi sample
+#[derive(Debug, Clone)]
+pub struct NvStat {
+    pub index: usize,
+    pub uuid: String,
+    pub temp: Option<usize>,
+    pub fan: Option<usize>,
+}
+
+/// Fans of all GPUs from nvidia-settings
+#[derive(Debug, Clone, Default)]
+struct NvFans {
+    /// Fan numbers by GPU UUID in upper case
+    fans: BTreeMap<String, Vec<usize>>,
+    /// RPM by fan number
+    rpm: HashMap<usize, usize>,
+}
+
+#[derive(Debug)]
+struct NvSample<T> {
+    taken: Instant,
+    data: Result<T, String>,
+}
+
+#[derive(Debug)]
+struct NvSampler<T> {
+    /// Last finished sample
+    sample: Option<NvSample<T>>,
+    /// When running query was started
+    started: Option<Instant>,
+}
+
+/// Fan change for GPU writer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NvCommand {
+    /// Take manual control and set target speed
+    Speed(usize),
+    /// Return fan control to driver
+    Restore,
+}
+
+#[derive(Debug, Default)]
+struct NvJob {
+    /// Command waiting for writer, newer command replaces it
+    next: Option<NvCommand>,
+    running: bool,
+    /// Error of last finished command, reported by next pwm_set
+    error: Option<String>,
+}
+
+/// Queue of fan commands of single GPU.
+/// nvidia-settings runs in writer thread, so slow or hanging call would not stop control loop
+#[derive(Debug, Default)]
+struct NvWriter {
+    job: Mutex<NvJob>,
+    changed: Condvar,
+}
+
 #[derive(Debug, Clone)]
 pub struct PropellerNv {
     id: i32,
+    /// GPU UUID, stable GPU id for nvidia-smi
+    uuid: String,
     // speed: usize,
     min: usize,
-    max: usize,
+    /// Zero RPM mode, fan may be stopped below min
+    zero: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct ThermometerNv {
     id: i32,
+    uuid: String,
 }
 
-pub fn nv_termometer_from(id: i32) -> Result<Box<Thermometer>, String> {
-    Ok(Box::new(ThermometerNv { id: id }))
+/// Create thermometer for GPU with provided index, UUID or PCI bus id
+pub fn nv_termometer_from(key: &str) -> Result<Box<dyn Thermometer>, String> {
+    match nv_devices().into_iter().find(|d| d.matches(key)) {
+        Some(d) => Ok(Box::new(ThermometerNv {
+            id: d.id,
+            uuid: d.uuid.unwrap_or_default(),
+        })),
+        None => Err(format!("Can not find Nvidia GPU {}", key)),
+    }
 }
 /*
-    let test = "GPU 0: GeForce GTX 1070 (UUID: GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49)
-GPU 1: GeForce GTX 1060 3GB (UUID: GPU-51e5ba3b-3f42-d70b-075b-06b36565f091)
-GPU 2: GeForce GTX 1060 3GB (UUID: GPU-920ff0df-2fb4-b27d-76e1-08d7e9bb4f0c)
-GPU 3: GeForce GTX 1070 (UUID: GPU-beabe8a7-de7e-f455-baac-eb473505320d)  
+    let test = "0, GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49, 00000000:01:00.0, GeForce GTX 1070
+1, GPU-51e5ba3b-3f42-d70b-075b-06b36565f091, 00000000:03:00.0, GeForce GTX 1060 3GB
+2, GPU-920ff0df-2fb4-b27d-76e1-08d7e9bb4f0c, 00000000:04:00.0, GeForce GTX 1060 3GB
+3, GPU-beabe8a7-de7e-f455-baac-eb473505320d, 00000000:05:00.0, GeForce GTX 1070
 ";
     return create_devices(&String::from(test));
 */
 
 pub fn nv_devices() -> Vec<Device> {
-    let rout = Command::new("nvidia-smi").arg("--list-gpus").output();
+    let rout = run_cmd(
+        Command::new("nvidia-smi")
+            .arg("--query-gpu=index,uuid,pci.bus_id,name")
+            .arg("--format=csv,noheader"),
+        SMI_TIMEOUT,
+    );
 
-    if rout.is_ok() {
-        let resout = rout.unwrap();
-        let out = String::from(String::from_utf8_lossy(&resout.stdout));
-        create_devices(&out)
-    } else {
-        println!("ERROR: Can not execute nvidia-smi {}", rout.err().unwrap());
-        Vec::new()
+    match rout {
+        Ok(resout) => {
+            let out = String::from(String::from_utf8_lossy(&resout.stdout));
+            create_devices(&out)
+        }
+        Err(e) => {
+            println!("ERROR: Can not execute nvidia-smi {}", e);
+            Vec::new()
+        }
     }
 }
 
-fn create_devices(nvout: &String) -> Vec<Device> {
+fn create_devices(nvout: &str) -> Vec<Device> {
     let re = Regex::new(
-        r"(?m)^\s*GPU\s+(?P<id>\d+):\s+(GeForce\s+)*(?P<name>.+\S+)\s*\(UUID:.+$",
+        r"(?m)^\s*(?P<id>\d+),\s*(?P<uuid>GPU-[[:xdigit:]-]+),\s*(?P<bus>[[:xdigit:]:.]+),\s*(GeForce\s+)*(?P<name>.+\S+)\s*$",
     ).unwrap();
 
     re.captures_iter(nvout)
         .map(|caps| {
             create_device(
                 String::from(&caps["id"]).parse::<i32>().unwrap_or(-1),
+                String::from(&caps["uuid"]),
+                pci_slot(&caps["bus"]),
                 String::from(&caps["name"]),
             )
         })
         .collect()
 }
 
-fn create_device(nv_id: i32, name: String) -> Device {
+fn create_device(nv_id: i32, uuid: String, pci: String, name: String) -> Device {
     Device {
         dev_type: String::from("nv"),
         id: nv_id,
-        name: name,
-        termometers: vec![Box::new(ThermometerNv { id: nv_id })],
+        name,
+        path: None,
+        pci: Some(pci),
+        uuid: Some(uuid.clone()),
+        driver: Some(String::from("nvidia")),
+        card: None,
+        termometers: vec![Box::new(ThermometerNv {
+            id: nv_id,
+            uuid: uuid.clone(),
+        })],
         propeller: Some(Box::new(PropellerNv {
             id: nv_id,
+            uuid,
             min: 0,
-            max: 100,
+            zero: false,
         })),
     }
 }
 
+/// Current state of GPU with provided UUID.
+/// All GPUs are sampled with single nvidia-smi call, that is reused for SAMPLE_TTL.
+/// If nvidia-smi hangs, callers wait for it no longer than SAMPLE_WAIT
+pub fn nv_stat(uuid: &str) -> Result<NvStat, String> {
+    let mut sampler = nv_sample(&SAMPLER, &SAMPLER_READY, nv_query);
+
+    while let Some(started) = sampler.started {
+        match SAMPLE_WAIT.checked_sub(started.elapsed()) {
+            Some(left) => sampler = SAMPLER_READY.wait_timeout(sampler, left).unwrap().0,
+            None => break,
+        }
+    }
+
+    match sampler.sample {
+        Some(ref s) if s.taken.elapsed() < SAMPLE_MAX_AGE => match s.data {
+            Ok(ref stats) => stats
+                .get(uuid)
+                .cloned()
+                .ok_or(format!("Can not find GPU {} in nvidia-smi output", uuid)),
+            Err(ref e) => Err(e.clone()),
+        },
+        Some(ref s) => Err(format!(
+            "nvidia-smi does not respond, last sample is {} sec old",
+            s.taken.elapsed().as_secs()
+        )),
+        None => Err(String::from("nvidia-smi does not respond")),
+    }
+}
+
+/// Lock sampler and start query in background thread if last sample is older than SAMPLE_TTL
+fn nv_sample<T: Send + 'static>(
+    sampler: &'static Mutex<NvSampler<T>>,
+    ready: &'static Condvar,
+    query: fn() -> Result<T, String>,
+) -> MutexGuard<'static, NvSampler<T>> {
+    let mut s = sampler.lock().unwrap();
+
+    let fresh = s.sample.as_ref().is_some_and(|s| s.taken.elapsed() < SAMPLE_TTL);
+    if !fresh && s.started.is_none() {
+        s.started = Some(Instant::now());
+        thread::spawn(move || {
+            let data = query();
+            let mut s = sampler.lock().unwrap();
+            s.sample = Some(NvSample {
+                taken: Instant::now(),
+                data,
+            });
+            s.started = None;
+            ready.notify_all();
+        });
+    }
+    s
+}
+
+fn nv_query() -> Result<HashMap<String, NvStat>, String> {
+    let out = run_cmd(
+        Command::new("nvidia-smi")
+            .arg("--query-gpu=index,uuid,temperature.gpu,fan.speed")
+            .arg("--format=csv,noheader,nounits"),
+        SMI_TIMEOUT,
+    )?;
+
+    if !out.status.success() {
+        return Err(format!(
+            "nvidia-smi failed {} {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+
+    Ok(parse_stats(&String::from_utf8_lossy(&out.stdout)))
+}
+
+/// Parse csv lines like "0, GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49, 65, 40"
+/// Unsupported values like [N/A] are ignored
+fn parse_stats(nvout: &str) -> HashMap<String, NvStat> {
+    let num = |v: Option<&str>| v.and_then(|v| v.replace("%", "").trim().parse::<usize>().ok());
+
+    nvout
+        .lines()
+        .filter_map(|l| {
+            let mut cols = l.split(',').map(|c| c.trim());
+            let index = cols.next()?.parse::<usize>().ok()?;
+            let uuid = String::from(cols.next()?);
+            Some(NvStat {
+                index,
+                uuid,
+                temp: num(cols.next()),
+                fan: num(cols.next()),
+            })
+        })
+        .map(|st| (st.uuid.clone(), st))
+        .collect()
+}
+
+/// Fans connected to GPU with upper case UUID.
+/// Fans are listed once by `nvidia-settings -q gpus`, list is taken again if GPU is not in it
+fn nv_fans(gpu: &str) -> Result<Vec<usize>, String> {
+    if let Some(fans) = FANS.lock().unwrap().get(gpu) {
+        return Ok(fans.clone());
+    }
+
+    let out = run_cmd(Command::new("nvidia-settings").arg("-q").arg("gpus"), SETTINGS_TIMEOUT)?;
+    if !out.status.success() {
+        return Err(format!(
+            "nvidia-settings failed {} {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let mut all = FANS.lock().unwrap();
+    *all = parse_gpu_fans(&String::from_utf8_lossy(&out.stdout));
+    match all.get(gpu) {
+        Some(fans) if !fans.is_empty() => Ok(fans.clone()),
+        Some(_) => Err(format!("GPU {} has no fans in nvidia-settings", gpu)),
+        None => Err(format!("Can not find GPU {} in nvidia-settings", gpu)),
+    }
+}
+
+/// Parse `nvidia-settings -q gpus` listing, every GPU block starts with `[N] host:0[gpu:N]`
+/// and has its UUID among names and `[fan:N]` lines of connected fans
+fn parse_gpu_fans(out: &str) -> BTreeMap<String, Vec<usize>> {
+    let gpu_re = Regex::new(r"^\s*\[\d+\]\s+\S*\[gpu:\d+\]").unwrap();
+    let uuid_re = Regex::new(r"^\s*(GPU-[[:xdigit:]]{8}-[[:xdigit:]-]+)\s*$").unwrap();
+    let fan_re = Regex::new(r"^\s*\[fan:(\d+)\]").unwrap();
+
+    let mut res = BTreeMap::new();
+    let mut uuid: Option<String> = None;
+    let mut fans: Vec<usize> = Vec::new();
+    for l in out.lines() {
+        if gpu_re.is_match(l) {
+            if let Some(u) = uuid.take() {
+                res.insert(u, fans.clone());
+            }
+            fans.clear();
+        } else if let Some(c) = uuid_re.captures(l) {
+            uuid = Some(c[1].to_uppercase());
+        } else if let Some(c) = fan_re.captures(l) {
+            fans.extend(c[1].parse::<usize>().ok());
+        }
+    }
+    if let Some(u) = uuid {
+        res.insert(u, fans);
+    }
+    res
+}
+
+/// Query fans of all GPUs and their RPM with single nvidia-settings call.
+/// nvidia-settings fails without X display, then fans are not known
+fn nv_fan_query() -> Result<NvFans, String> {
+    let out = run_cmd(
+        Command::new("nvidia-settings")
+            .arg("-q")
+            .arg("gpus")
+            .arg("-q")
+            .arg("GPUCurrentFanSpeedRPM"),
+        SETTINGS_TIMEOUT,
+    )?;
+    if !out.status.success() {
+        return Ok(NvFans::default());
+    }
+
+    let text = String::from_utf8_lossy(&out.stdout);
+    let fans = NvFans {
+        fans: parse_gpu_fans(&text),
+        rpm: parse_fan_rpm(&text),
+    };
+    if !fans.fans.is_empty() {
+        *FANS.lock().unwrap() = fans.fans.clone();
+    }
+    Ok(fans)
+}
+
+/// Parse lines like "  Attribute 'GPUCurrentFanSpeedRPM' (rig:0[fan:1]): 1490."
+fn parse_fan_rpm(out: &str) -> HashMap<usize, usize> {
+    let re = Regex::new(r"Attribute 'GPUCurrentFanSpeedRPM' \(\S*\[fan:(\d+)\]\):\s*(\d+)").unwrap();
+    re.captures_iter(out)
+        .filter_map(|c| Some((c[1].parse::<usize>().ok()?, c[2].parse::<usize>().ok()?)))
+        .collect()
+}
+
+impl NvFans {
+    /// RPM of slowest fan of GPU, None if GPU has no fans with RPM
+    fn gpu_rpm(&self, uuid: &str) -> Option<usize> {
+        self.fans
+            .get(&uuid.to_uppercase())?
+            .iter()
+            .filter_map(|f| self.rpm.get(f).cloned())
+            .min()
+    }
+}
+
 impl Thermometer for ThermometerNv {
-    fn box_clone(&self) -> Box<Thermometer> {
+    fn box_clone(&self) -> Box<dyn Thermometer> {
         Box::new((*self).clone())
     }
 
-    fn temp(&self) -> usize {
-        let rout = Command::new("nvidia-smi")
-            .arg("--query-gpu=temperature.gpu")
-            .arg("--format=csv,noheader")
-            .arg("-i")
-            .arg(format!("{}", self.id))
-            .output();
+    fn temp(&self) -> Result<usize, String> {
+        nv_stat(&self.uuid)
+            .and_then(|st| st.temp.ok_or(String::from("temperature is not supported")))
+            .map_err(|e| format!("NV#{} Can not read temperature {}", self.id, e))
+    }
+}
 
-        if rout.is_ok() {
-            let resout = rout.unwrap();
-            let out = String::from(String::from_utf8_lossy(&resout.stdout));
-            out.trim().parse::<usize>().unwrap_or(0)
-        } else {
-            println!(
-                "ERROR: NV#{} Can not read temperature nvidia-smi {}",
-                self.id,
-                rout.err().unwrap()
-            );
-            0
+
+impl NvWriter {
+    /// Writer of GPU, its thread is started on first use
+    fn of(id: i32, uuid: &str) -> Arc<NvWriter> {
+        let mut writers = WRITERS.lock().unwrap();
+        if let Some(w) = writers.get(uuid) {
+            return w.clone();
+        }
+
+        let w = Arc::new(NvWriter::default());
+        writers.insert(String::from(uuid), w.clone());
+        let (tw, uuid) = (w.clone(), String::from(uuid));
+        thread::spawn(move || tw.run(id, &uuid));
+        w
+    }
+
+    /// Queue command, not started command is dropped.
+    /// Error of previous command is returned
+    fn send(&self, cmd: NvCommand) -> Option<String> {
+        let mut job = self.job.lock().unwrap();
+        job.next = Some(cmd);
+        self.changed.notify_all();
+        job.error.take()
+    }
+
+    /// Wait until queue is empty, false on timeout
+    fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut job = self.job.lock().unwrap();
+        while job.running || job.next.is_some() {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => job = self.changed.wait_timeout(job, left).unwrap().0,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn run(&self, id: i32, uuid: &str) {
+        loop {
+            let cmd = {
+                let mut job = self.job.lock().unwrap();
+                while job.next.is_none() {
+                    job = self.changed.wait(job).unwrap();
+                }
+                job.running = true;
+                job.next.take().unwrap()
+            };
+
+            let res = nv_command(id, uuid, cmd);
+            let mut job = self.job.lock().unwrap();
+            job.running = false;
+            job.error = res.err();
+            self.changed.notify_all();
         }
     }
 }
 
+/// Run nvidia-settings for fan command.
+/// GPU is addressed by UUID, target speed is set for every fan connected to it
+fn nv_command(id: i32, uuid: &str, cmd: NvCommand) -> Result<(), String> {
+    let gpu = uuid.to_uppercase();
+    let mut nvs = Command::new("nvidia-settings");
+    match cmd {
+        NvCommand::Speed(val) => {
+            nvs.arg("-a").arg(format!("[gpu:{}]/GPUFanControlState=1", gpu));
+            for fan in nv_fans(&gpu).map_err(|e| format!("NV#{} {}", id, e))? {
+                nvs.arg("-a").arg(format!("[fan:{}]/GPUTargetFanSpeed={}", fan, val));
+            }
+        }
+        NvCommand::Restore => {
+            nvs.arg("-a").arg(format!("[gpu:{}]/GPUFanControlState=0", gpu));
+        }
+    };
+
+    let what = match cmd {
+        NvCommand::Speed(val) => format!("change fan to {}", val),
+        NvCommand::Restore => String::from("restore fan control"),
+    };
+    match run_cmd(&mut nvs, SETTINGS_TIMEOUT) {
+        Ok(ref o) if o.status.success() => Ok(()),
+        Ok(o) => Err(format!(
+            "NV#{} Can not {}. {}",
+            id,
+            what,
+            String::from_utf8_lossy(&o.stderr).trim()
+        )),
+        Err(e) => Err(format!("NV#{} Can not {} {}", id, what, e)),
+    }
+}
+
+/// Wait until queued fan commands of all GPUs are done, used before exit
+pub fn nv_flush(timeout: Duration) {
+    let writers: Vec<Arc<NvWriter>> = WRITERS.lock().unwrap().values().cloned().collect();
+    let deadline = Instant::now() + timeout;
+    for w in writers {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if !w.wait(left) {
+            println!("ERROR nvidia-settings does not respond, fan commands are not finished");
+            return;
+        }
+    }
+}
 
 impl Propeller for PropellerNv {
-    fn box_clone(&self) -> Box<Propeller> {
+    fn box_clone(&self) -> Box<dyn Propeller> {
         Box::new((*self).clone())
     }
 
     fn pwm(&self) -> Result<usize, String> {
-        let rout = Command::new("nvidia-smi")
-            .arg("--query-gpu=fan.speed")
-            .arg("--format=csv,noheader")
-            .arg("-i")
-            .arg(format!("{}", self.id))
-            .output();
-
-        if rout.is_ok() {
-            let resout = rout.unwrap();
-            let out = String::from(String::from_utf8_lossy(&resout.stdout));
-            // 95 %
-            let po = out.replace("%", "").trim().parse::<usize>();
-            if po.is_ok() {
-                Ok(po.unwrap())
-            } else {
-                Err(format!("NV#{} Can not read  fan speed", self.id))
-            }
-        } else {
-            Err(format!(
-                "NV#{} Can not read pwm speed. {}",
-                self.id,
-                rout.err().unwrap()
-            ))
-        }
+        nv_stat(&self.uuid)
+            .and_then(|st| st.fan.ok_or(String::from("fan speed is not supported")))
+            .map_err(|e| format!("NV#{} Can not read pwm speed. {}", self.id, e))
     }
 
     fn pwm_set(&self, val: usize) -> Result<usize, String> {
         let mut nval = val;
-        if val > 100 || val > self.max {
-            nval = self.max;
+        if val > 100 {
+            nval = 100;
         }
         /* val < 0 || */
-        if val < self.min {
+        if val < self.min && !(val == 0 && self.zero) {
             nval = self.min;
         }
 
-        let mut cmd = Command::new("nvidia-settings");
-        cmd.arg("-a").arg(format!(
-            "[gpu:{}]/GPUFanControlState=1",
-            self.id
-        ));
+        // Speed is changed in background, error of previous change is reported now
+        match NvWriter::of(self.id, &self.uuid).send(NvCommand::Speed(nval)) {
+            Some(e) => Err(e),
+            None => Ok(nval),
+        }
+    }
 
-        let out = cmd.arg("-a")
-            .arg(format!("[fan:{}]/GPUTargetFanSpeed={}", self.id, nval))
-            .output();
-
-        match out {
-            Ok(o) => {
-                if o.status.success() {
-                    #[cfg(debug_assertions)]
-                    {
-                        println!(
-                            "NV#{} PWM updated to {} now value {}",
-                            self.id,
-                            nval,
-                            self.pwm().unwrap_or(0)
-                        );
-                    }
-                    self.pwm()
-                } else {
-                    Err(format!(
-                        "NV#{} Change fan fail. {}",
-                        self.id,
-                        String::from_utf8_lossy(&o.stderr)
-                    ))
-                }
-            }
-            Err(e) => Err(format!(
-                "NV#{} Can not chage fan to {} {}",
+    fn configure(&mut self, set: &Settings) {
+        self.min = set.pwm_min;
+        self.zero = set.zero_rpm_temp.is_some();
+    }
+
+    fn restore(&self) -> Result<String, String> {
+        let w = NvWriter::of(self.id, &self.uuid);
+        w.send(NvCommand::Restore);
+        if !w.wait(RESTORE_WAIT) {
+            return Ok(format!("NV#{} GPUFanControlState=0 is queued", self.id));
+        }
+        let error = w.job.lock().unwrap().error.take();
+        match error {
+            Some(e) => Err(e),
+            None => Ok(format!("NV#{} GPUFanControlState=0", self.id)),
+        }
+    }
+
+    fn pwm_leave(&self, val: usize) -> Result<usize, String> {
+        // Fan control state stays manual until nvidia-settings changes it
+        self.pwm_set(val)
+    }
+
+    /// RPM is reported by nvidia-settings only, not every GPU and driver supports it.
+    /// Slowest fan of GPU is reported from last background sample
+    fn rpm(&self) -> Result<Option<usize>, String> {
+        let sampler = nv_sample(&FAN_SAMPLER, &FAN_SAMPLER_READY, nv_fan_query);
+        match sampler.sample {
+            Some(ref s) if s.taken.elapsed() < SAMPLE_MAX_AGE => match s.data {
+                Ok(ref f) => Ok(f.gpu_rpm(&self.uuid)),
+                Err(ref e) => Err(format!("NV#{} Can not read fan RPM {}", self.id, e)),
+            },
+            Some(ref s) => Err(format!(
+                "NV#{} Can not read fan RPM, nvidia-settings does not respond, last sample is {} sec old",
                 self.id,
-                nval,
-                e
+                s.taken.elapsed().as_secs()
             )),
+            // First sample is not taken yet
+            None => Ok(None),
         }
     }
+}
 
-    fn configure(&mut self, set: &Settings) {
-        self.min = set.pwm_min;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_are_parsed() {
+        let out = "0, GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49, 65, 40\n\
+                   1, GPU-51e5ba3b-3f42-d70b-075b-06b36565f091, 58, [N/A]\n\
+                   No devices were found\n";
+        let stats = parse_stats(out);
+        assert_eq!(stats.len(), 2);
+
+        let st = &stats["GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49"];
+        assert_eq!((st.index, st.temp, st.fan), (0, Some(65), Some(40)));
+        let st = &stats["GPU-51e5ba3b-3f42-d70b-075b-06b36565f091"];
+        assert_eq!((st.index, st.temp, st.fan), (1, Some(58), None));
     }
-}
\ No newline at end of file
+
+    const GPUS: &str = "
+2 GPUs on rig:0
+
+    [0] rig:0[gpu:0] (GeForce GTX 1070)
+
+      Has the following names:
+        GPU-0
+        GPU-09B509F0-961C-189E-BF2E-A1FD2D999B49
+
+      Is connected to the following fans:
+          [fan:0] (Fan 0)
+          [fan:1] (Fan 1)
+
+    [1] rig:0[gpu:1] (GeForce GTX 1060 3GB)
+
+      Has the following names:
+        GPU-1
+        GPU-51E5BA3B-3F42-D70B-075B-06B36565F091
+
+      Is connected to the following fans:
+          [fan:2] (Fan 2)
+
+  Attribute 'GPUCurrentFanSpeedRPM' (rig:0[fan:0]): 1510.
+  Attribute 'GPUCurrentFanSpeedRPM' (rig:0[fan:1]): 1490.
+  Attribute 'GPUCurrentFanSpeedRPM' (rig:0[fan:2]): 2100.
+";
+
+    #[test]
+    fn fans_of_gpus_are_parsed() {
+        let fans = parse_gpu_fans(GPUS);
+        assert_eq!(fans.len(), 2);
+        assert_eq!(fans["GPU-09B509F0-961C-189E-BF2E-A1FD2D999B49"], vec![0, 1]);
+        assert_eq!(fans["GPU-51E5BA3B-3F42-D70B-075B-06B36565F091"], vec![2]);
+    }
+
+    #[test]
+    fn slowest_fan_of_gpu_is_reported() {
+        let fans = NvFans {
+            fans: parse_gpu_fans(GPUS),
+            rpm: parse_fan_rpm(GPUS),
+        };
+        assert_eq!(fans.rpm.len(), 3);
+        assert_eq!(fans.gpu_rpm("GPU-09b509f0-961c-189e-bf2e-a1fd2d999b49"), Some(1490));
+        assert_eq!(fans.gpu_rpm("GPU-51e5ba3b-3f42-d70b-075b-06b36565f091"), Some(2100));
+        assert_eq!(fans.gpu_rpm("GPU-920ff0df-2fb4-b27d-76e1-08d7e9bb4f0c"), None);
+    }
+}
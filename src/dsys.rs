@@ -7,26 +7,45 @@ use core::Thermometer;
 use core::Settings;
 use core::read_file;
 use core::read_file_val;
-use core::read_file_val_or;
+use core::write_file_val;
 
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write;
 // use std::marker::Sized;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 use regex::Regex;
 
-pub static DIR_DEVICES: &'static str = "/sys/class/hwmon";
+/// Default mount point of sysfs, absolute paths in config are relative to it
+pub static SYS_ROOT: &str = "/sys";
+/// Directory with hwmon devices relative to sysfs root
+pub static DIR_DEVICES: &str = "class/hwmon";
 static TEMP_SCALE: usize = 1000;
 static FAN_SCALE: f64 = 2.55;
+/// pwmN_enable value for manual fan control
+static PWM_MANUAL: usize = 1;
 
 
-pub fn sys_devices() -> Vec<Device> {
-    let base = Path::new(DIR_DEVICES);
+/// Path to hwmon devices directory for provided sysfs root
+pub fn sys_dir_devices(root: &Path) -> PathBuf {
+    root.join(DIR_DEVICES)
+}
+
+/// Rebase absolute path from /sys onto provided sysfs root.
+/// Relative paths and paths outside of /sys are returned as is.
+pub fn sys_path(root: &Path, p: &Path) -> PathBuf {
+    match p.strip_prefix(SYS_ROOT) {
+        Ok(rel) => root.join(rel),
+        Err(_) => p.to_path_buf(),
+    }
+}
+
+pub fn sys_devices(root: &Path) -> Vec<Device> {
+    let base = sys_dir_devices(root);
     if !base.exists() || !base.is_dir() {
-        println!("ERROR: Can not read directory {}", DIR_DEVICES);
+        println!("ERROR: Can not read directory {}", base.to_string_lossy());
         return Vec::new();
     }
 
@@ -36,7 +55,7 @@ pub fn sys_devices() -> Vec<Device> {
         println!("READING {:?}", base);
     }
 
-    let mut paths: Vec<Device> = fs::read_dir(base)
+    let mut paths: Vec<Device> = fs::read_dir(&base)
         .unwrap()
         .map(|r| r.unwrap())
         .map(|it| create_device(&it.path()))
@@ -44,15 +63,14 @@ pub fn sys_devices() -> Vec<Device> {
 
     paths.sort_by_key(|p| p.id);
 
-    return paths;
+    paths
 }
 
-fn create_device(dir: &PathBuf) -> Device {
+fn create_device(dir: &Path) -> Device {
     let reavg = Regex::new(r"hwmon(\d+)[/\s]*$").unwrap();
-    let co = reavg.captures(&dir.to_str().unwrap());
+    let co = reavg.captures(dir.to_str().unwrap());
 
-    let mut npath = dir.clone();
-    npath.push("name");
+    let npath = dir.join("name");
 
     let fval = read_file(&npath).unwrap_or(String::from("?!?"));
     let dn = format!(
@@ -62,27 +80,65 @@ fn create_device(dir: &PathBuf) -> Device {
     );
 
     Device {
-        id: if co.is_some() {
-            let ts = String::from(co.unwrap().get(1).unwrap().as_str());
+        id: if let Some(c) = co {
+            let ts = String::from(c.get(1).unwrap().as_str());
             ts.parse::<i32>().unwrap_or(-1)
         } else {
             println!("ERROR can not find id in {}", dir.to_str().unwrap());
             -1
         },
         dev_type: String::from("sys"),
-        name: String::from(dn),
+        name: dn,
+        path: Some(dir.to_path_buf()),
+        pci: sys_pci_slot(dir),
+        uuid: None,
+        driver: match fval.trim() {
+            "?!?" | "" => None,
+            v => Some(String::from(v)),
+        },
+        card: sys_drm_card(dir),
         termometers: Vec::new(),
         propeller: None,
     }
 }
 
+/// PCI slot of hwmon device, resolved through device symlink.
+/// For non PCI devices (like nvme) nearest PCI parent is used.
+fn sys_pci_slot(dir: &Path) -> Option<String> {
+    let re = Regex::new(r"^[[:xdigit:]]{4}:[[:xdigit:]]{2}:[[:xdigit:]]{2}\.[0-7]$").unwrap();
+    let link = dir.join("device");
+    let target = fs::canonicalize(&link).or_else(|_| fs::read_link(&link)).ok()?;
+
+    target
+        .components()
+        .rev()
+        .filter_map(|c| c.as_os_str().to_str())
+        .find(|c| re.is_match(c))
+        .map(String::from)
+}
+
+/// DRM card of hwmon device like card0, if it is GPU
+fn sys_drm_card(dir: &Path) -> Option<String> {
+    let re = Regex::new(r"^card\d+$").unwrap();
+    let mut cards: Vec<String> = fs::read_dir(dir.join("device").join("drm"))
+        .ok()?
+        .filter_map(|r| r.ok())
+        .filter_map(|it| it.file_name().to_str().map(String::from))
+        .filter(|n| re.is_match(n))
+        .collect();
+    cards.sort();
+    cards.into_iter().next()
+}
+
 /// Update device with provided settings
 /// Add thermometers and propellers if any
 pub fn sys_device_update(d: &Device, set: &Settings) -> Device {
     let mut dd = d.clone();
 
-    let mut p = PathBuf::from(DIR_DEVICES);
-    p.push(format!("hwmon{}", d.id));
+    let mut p = match d.path {
+        Some(ref dp) => dp.clone(),
+        None => sys_dir_devices(&set.sys_root).join(format!("hwmon{}", d.id)),
+    };
     let pd = p.clone();
     p.push(set.sys_pwm_file.clone());
 
@@ -104,9 +160,9 @@ pub fn sys_device_update(d: &Device, set: &Settings) -> Device {
     dd
 }
 
-pub fn sys_termometer_from(p: &PathBuf) -> Result<Box<Thermometer>, String> {
+pub fn sys_termometer_from(p: &Path) -> Result<Box<dyn Thermometer>, String> {
     if p.exists() {
-        Ok(Box::new(ThermometerSys { temp_file: p.clone() }))
+        Ok(Box::new(ThermometerSys { temp_file: p.to_path_buf() }))
     } else {
         Err(format!(
             "ERROR! TEMP file does not exist {}",
@@ -115,24 +171,155 @@ pub fn sys_termometer_from(p: &PathBuf) -> Result<Box<Thermometer>, String> {
     }
 }
 
-pub fn sys_propeller_from(p: &PathBuf, set: &Settings) -> Result<Box<Propeller>, String> {
+pub fn sys_propeller_from(p: &Path, set: &Settings) -> Result<Box<dyn Propeller>, String> {
     if !p.exists() {
         return Err(format!("PWM file does not exist {}", p.to_string_lossy()));
     }
 
     Ok(Box::new(PropellerSys {
-        pfile: p.clone(),
+        pfile: p.to_path_buf(),
+        tach: sys_tach_file(p),
+        enable: PwmEnable::from(p).map(Arc::new),
         min: set.pwm_min,
-        max: 100,
+        zero: set.zero_rpm_temp.is_some(),
     }))
 }
 
+/// Tachometer fanN_input next to pwmN file, if device has it
+fn sys_tach_file(pfile: &Path) -> Option<PathBuf> {
+    let name = pfile.file_name()?.to_str()?;
+    let n = name.strip_prefix("pwm")?;
+    if n.is_empty() || !n.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let tach = pfile.with_file_name(format!("fan{}_input", n));
+    if tach.exists() { Some(tach) } else { None }
+}
+
 #[derive(Debug, Clone)]
 pub struct PropellerSys {
     pfile: PathBuf,
+    /// fanN_input with RPM
+    tach: Option<PathBuf>,
+    /// Shared between clones, original mode is restored when last clone dropped
+    enable: Option<Arc<PwmEnable>>,
     // speed: usize,
     min: usize,
-    max: usize,
+    /// Zero RPM mode, fan may be stopped below min
+    zero: bool,
+}
+
+/// Control mode of pwmN file through pwmN_enable.
+/// 0 - no control (full speed), 1 - manual, 2+ - automatic (driver/firmware)
+#[derive(Debug)]
+struct PwmEnable {
+    efile: PathBuf,
+    state: Mutex<EnableState>,
+}
+
+#[derive(Debug)]
+struct EnableState {
+    /// Mode that was set before we took control, None if already restored
+    origin: Option<usize>,
+    /// True when we have switched device into manual mode
+    manual: bool,
+}
+
+impl PwmEnable {
+    /// Read pwmN_enable mode for pwmN file if any
+    fn from(pfile: &Path) -> Option<PwmEnable> {
+        let mut name = pfile.file_name()?.to_os_string();
+        name.push("_enable");
+        let efile = pfile.with_file_name(name);
+
+        match read_file_val::<usize>(&efile) {
+            Ok(mode) => Some(PwmEnable {
+                efile,
+                state: Mutex::new(EnableState {
+                    origin: Some(mode),
+                    manual: false,
+                }),
+            }),
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                {
+                    println!("No pwm enable file {} {}", efile.to_string_lossy(), e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Switch to manual mode if device is not in it yet.
+    /// Would complain if somebody else changed mode after we took control
+    fn manual(&self) -> Result<(), String> {
+        let mut st = self.state.lock().unwrap();
+        let mode = read_file_val::<usize>(&self.efile)?;
+        if mode == PWM_MANUAL {
+            if st.origin.is_none() {
+                st.origin = Some(mode);
+            }
+            st.manual = true;
+            return Ok(());
+        }
+
+        if st.manual {
+            println!(
+                "WARNING {} changed to {} by driver, taking manual control back",
+                self.efile.to_string_lossy(),
+                mode
+            );
+        }
+        if st.origin.is_none() {
+            st.origin = Some(mode);
+        }
+
+        write_file_val(&self.efile, PWM_MANUAL)?;
+        let now = read_file_val::<usize>(&self.efile)?;
+        if now != PWM_MANUAL {
+            return Err(format!(
+                "Can not switch {} to manual mode, it stays at {}",
+                self.efile.to_string_lossy(),
+                now
+            ));
+        }
+        st.manual = true;
+        Ok(())
+    }
+
+    /// Forget original mode, so device would stay in manual mode after exit
+    fn keep(&self) {
+        let mut st = self.state.lock().unwrap();
+        st.origin = None;
+        st.manual = false;
+    }
+
+    /// Put back mode that was set before we took control
+    fn restore(&self) -> Result<String, String> {
+        let mut st = self.state.lock().unwrap();
+        match st.origin {
+            Some(mode) => {
+                write_file_val(&self.efile, mode)?;
+                st.origin = None;
+                st.manual = false;
+                Ok(format!("{}={}", self.efile.to_string_lossy(), mode))
+            }
+            None => Ok(format!("{} already restored", self.efile.to_string_lossy())),
+        }
+    }
+}
+
+impl Drop for PwmEnable {
+    fn drop(&mut self) {
+        let manual = self.state.lock().map(|s| s.manual).unwrap_or(false);
+        if !manual {
+            return;
+        }
+        match self.restore() {
+            Ok(r) => println!("Restored {}", r),
+            Err(e) => println!("ERROR {}", e),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -149,61 +336,80 @@ fn scale_from_sys(sys: usize) -> usize {
 }
 
 impl Propeller for PropellerSys {
-    fn box_clone(&self) -> Box<Propeller> {
+    fn box_clone(&self) -> Box<dyn Propeller> {
         Box::new((*self).clone())
     }
 
     fn pwm(&self) -> Result<usize, String> {
-        read_file_val::<usize>(&self.pfile).map(|v| scale_from_sys(v))
+        if let Some(ref en) = self.enable {
+            // Make sure driver did not take control back
+            if en.state.lock().unwrap().manual {
+                en.manual()?;
+            }
+        }
+        read_file_val::<usize>(&self.pfile).map(scale_from_sys)
     }
 
     fn pwm_set(&self, val: usize) -> Result<usize, String> {
         let mut nval = scale_to_sys(val);
-        if val > 100 || val > self.max {
-            nval = scale_to_sys(self.max);
+        if val > 100 {
+            nval = scale_to_sys(100);
         }
         /* val < 0 || */
 
-        if val < self.min {
+        if val < self.min && !(val == 0 && self.zero) {
             nval = scale_to_sys(self.min);
         }
 
-        let mut fopts = OpenOptions::new();
-        fopts.write(true);
-
-        match fopts.open(self.pfile.clone()) {
-            Ok(mut f) => {
-                match write!(f, "{}", nval) {
-                    Ok(_) => Ok(scale_from_sys(nval)),
-                    Err(e) => Err(format!(
-                        "Can not write {} to {} {}",
-                        nval,
-                        self.pfile.to_str().unwrap_or_default(),
-                        e
-                    )),
-                }
-            }
-            Err(e) => Err(format!(
-                "Can not write {} to {} {}",
-                nval,
-                self.pfile.to_str().unwrap_or_default(),
-                e
-            )),
+        if let Some(ref en) = self.enable {
+            en.manual()?;
         }
+
+        write_file_val(&self.pfile, nval).map(|_| scale_from_sys(nval))
     }
 
     fn configure(&mut self, set: &Settings) {
         self.min = set.pwm_min;
+        self.zero = set.zero_rpm_temp.is_some();
+    }
+
+    fn restore(&self) -> Result<String, String> {
+        match self.enable {
+            Some(ref en) => en.restore(),
+            None => Err(format!(
+                "No pwm enable file for {}",
+                self.pfile.to_string_lossy()
+            )),
+        }
+    }
+
+    fn pwm_leave(&self, val: usize) -> Result<usize, String> {
+        let res = self.pwm_set(val)?;
+        if let Some(ref en) = self.enable {
+            en.keep();
+        }
+        Ok(res)
+    }
+
+    fn rpm(&self) -> Result<Option<usize>, String> {
+        match self.tach {
+            Some(ref t) => read_file_val::<usize>(t)
+                .map(Some)
+                .map_err(|e| format!("{} {}", t.to_string_lossy(), e)),
+            None => Ok(None),
+        }
     }
 }
 
 impl Thermometer for ThermometerSys {
-    fn box_clone(&self) -> Box<Thermometer> {
+    fn box_clone(&self) -> Box<dyn Thermometer> {
         Box::new((*self).clone())
     }
 
-    fn temp(&self) -> usize {
-        read_file_val_or(&self.temp_file, 0) / TEMP_SCALE
+    fn temp(&self) -> Result<usize, String> {
+        read_file_val::<usize>(&self.temp_file)
+            .map(|v| v / TEMP_SCALE)
+            .map_err(|e| format!("{} {}", self.temp_file.to_string_lossy(), e))
     }
 }
 
@@ -245,4 +451,73 @@ impl Thermometer for ThermometerSys {
 //             pmax = s.pwm_max;
 //         }
 //     }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    /// Fake sysfs with amdgpu hwmon2 on PCI slot 0000:03:00.0 and k10temp hwmon0 without PWM
+    fn sysfs(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("karlson-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let pci = root.join("devices/pci0000:00/0000:00:01.1/0000:03:00.0");
+        fs::create_dir_all(pci.join("drm/card1")).unwrap();
+
+        let gpu = root.join("class/hwmon/hwmon2");
+        fs::create_dir_all(&gpu).unwrap();
+        fs::write(gpu.join("name"), "amdgpu\n").unwrap();
+        fs::write(gpu.join("pwm1"), "0").unwrap();
+        fs::write(gpu.join("pwm1_enable"), "2").unwrap();
+        fs::write(gpu.join("temp1_input"), "45000").unwrap();
+        symlink(&pci, gpu.join("device")).unwrap();
+
+        let cpu = root.join("class/hwmon/hwmon0");
+        fs::create_dir_all(&cpu).unwrap();
+        fs::write(cpu.join("name"), "k10temp\n").unwrap();
+        root
+    }
+
+    #[test]
+    fn devices_are_found_with_pci_slot_and_card() {
+        let root = sysfs("devices");
+        let devs = sys_devices(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(devs.iter().map(|d| d.id).collect::<Vec<i32>>(), vec![0, 2]);
+        assert_eq!(devs[0].name, "hwmon0(k10temp)");
+        assert_eq!(devs[0].pci, None);
+        assert_eq!(devs[1].name, "hwmon2(amdgpu)");
+        assert_eq!(devs[1].pci, Some(String::from("0000:03:00.0")));
+        assert_eq!(devs[1].driver, Some(String::from("amdgpu")));
+        assert_eq!(devs[1].card, Some(String::from("card1")));
+    }
+
+    #[test]
+    fn pwm_enable_is_restored() {
+        let root = sysfs("enable");
+        let gpu = root.join("class/hwmon/hwmon2");
+        let enable = || read_file_val::<usize>(&gpu.join("pwm1_enable")).unwrap();
+
+        let p = sys_propeller_from(&gpu.join("pwm1"), &Settings::default()).unwrap();
+        assert_eq!(p.pwm_set(50), Ok(50));
+        assert_eq!(enable(), PWM_MANUAL);
+        assert_eq!(read_file_val::<usize>(&gpu.join("pwm1")), Ok(127));
+        assert!(p.restore().is_ok());
+        assert_eq!(enable(), 2);
+
+        // Last clone restores driver mode when dropped
+        let p = sys_propeller_from(&gpu.join("pwm1"), &Settings::default()).unwrap();
+        p.pwm_set(60).unwrap();
+        let clone = p.clone();
+        drop(p);
+        assert_eq!(enable(), PWM_MANUAL);
+        drop(clone);
+        assert_eq!(enable(), 2);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+// History recording
+// Every tick appends one sample per device to local file, rotated by size and age
+
+use config::HistoryConfig;
+use karlson::Karlson;
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// CSV columns, temperatures of all thermometers are space separated in one column
+const CSV_HEADER: &str = "time,device,name,temps,pwm_before,pwm,target,reason\n";
+
+#[derive(Debug)]
+pub struct History {
+    conf: HistoryConfig,
+    file: File,
+    /// Current file size in bytes
+    size: u64,
+    /// When current file was started
+    started: SystemTime,
+}
+
+impl History {
+    /// Open history file for appending, existing file is continued
+    pub fn open(conf: &HistoryConfig) -> Result<History, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&conf.path)
+            .map_err(|e| format!("Can not open history {} {}", conf.path.to_string_lossy(), e))?;
+        let meta = file.metadata().map_err(|e| e.to_string())?;
+
+        let mut h = History {
+            conf: conf.clone(),
+            file,
+            size: meta.len(),
+            started: meta.created().unwrap_or_else(|_| SystemTime::now()),
+        };
+        if h.size == 0 {
+            h.started = SystemTime::now();
+            h.header()?;
+        }
+        Ok(h)
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.conf
+    }
+
+    /// Append last spin of every device, rotate file before if it is due
+    pub fn record(&mut self, karlsons: &[Karlson], devices: &[Karlson]) {
+        if karlsons.is_empty() && devices.is_empty() {
+            return;
+        }
+        if self.rotate_due() {
+            if let Err(e) = self.rotate() {
+                println!("ERROR history rotation {}", e);
+            }
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut lines = String::new();
+        for k in karlsons.iter().chain(devices.iter()) {
+            lines.push_str(&match self.conf.format.as_ref() {
+                "json" => format_json(time, k),
+                _ => format_csv(time, k),
+            });
+        }
+
+        if let Err(e) = self.write(&lines) {
+            println!("ERROR history {} {}", self.conf.path.to_string_lossy(), e);
+        }
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), String> {
+        self.file.write_all(s.as_bytes()).map_err(|e| e.to_string())?;
+        self.size += s.len() as u64;
+        Ok(())
+    }
+
+    fn header(&mut self) -> Result<(), String> {
+        match self.conf.format.as_ref() {
+            "csv" => self.write(CSV_HEADER),
+            _ => Ok(()),
+        }
+    }
+
+    fn rotate_due(&self) -> bool {
+        let age = Duration::from_secs(self.conf.max_hours as u64 * 3600);
+        self.size > self.conf.max_mb as u64 * 1024 * 1024 ||
+            (self.conf.max_hours > 0 &&
+                 SystemTime::now()
+                     .duration_since(self.started)
+                     .map(|d| d > age)
+                     .unwrap_or(false))
+    }
+
+    /// Shift path.N-1 to path.N ... path to path.1, the oldest file is removed
+    fn rotate(&mut self) -> Result<(), String> {
+        let path = self.conf.path.clone();
+        let rotated = |i: usize| {
+            let mut p = path.clone().into_os_string();
+            p.push(format!(".{}", i));
+            PathBuf::from(p)
+        };
+
+        if self.conf.keep == 0 {
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+        } else {
+            let _ = fs::remove_file(rotated(self.conf.keep));
+            for i in (1..self.conf.keep).rev() {
+                let from = rotated(i);
+                if from.exists() {
+                    fs::rename(&from, rotated(i + 1)).map_err(|e| e.to_string())?;
+                }
+            }
+            fs::rename(&path, rotated(1)).map_err(|e| e.to_string())?;
+        }
+
+        let conf = self.conf.clone();
+        *self = History::open(&conf)?;
+        Ok(())
+    }
+}
+
+fn csv_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+fn format_csv(time: u64, k: &Karlson) -> String {
+    let temps: Vec<String> = k.temps.iter().map(|t| t.to_string()).collect();
+    format!(
+        "{},{}#{},{},{},{},{},{},{}\n",
+        time,
+        k.dev.dev_type,
+        k.dev.id,
+        csv_quote(&k.dev.name),
+        temps.join(" "),
+        k.pwm_before,
+        k.pwm_speed,
+        k.pwm_target,
+        csv_quote(&k.reason)
+    )
+}
+
+fn format_json(time: u64, k: &Karlson) -> String {
+    let v = json!({
+        "time": time,
+        "device": format!("{}#{}", k.dev.dev_type, k.dev.id),
+        "name": k.dev.name,
+        "temps": k.temps,
+        "pwm_before": k.pwm_before,
+        "pwm": k.pwm_speed,
+        "target": k.pwm_target,
+        "reason": k.reason,
+    });
+    format!("{}\n", v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::read_file;
+    use karlson::KarlsonBuilder;
+    use std::env;
+    use std::process;
+
+    fn conf(name: &str, format: &str, keep: usize) -> HistoryConfig {
+        let dir = env::temp_dir().join(format!("karlson-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        HistoryConfig {
+            path: dir.join("history"),
+            format: String::from(format),
+            max_mb: 1,
+            max_hours: 24,
+            keep,
+        }
+    }
+
+    /// Device without hardware, sys type would be looked up in real sysfs
+    fn fan() -> Karlson {
+        KarlsonBuilder::new("test", 2, "fan \"front\", left").build()
+    }
+
+    fn read(p: PathBuf) -> String {
+        read_file(&p).unwrap()
+    }
+
+    #[test]
+    fn samples_are_formatted() {
+        let mut k = fan();
+        k.temps = vec![61, 58];
+        assert_eq!(
+            format_csv(100, &k),
+            "100,test#2,\"fan \"\"front\"\", left\",61 58,0,0,60,\"start at pwm_ok\"\n"
+        );
+        let v: ::serde_json::Value = ::serde_json::from_str(&format_json(100, &k)).unwrap();
+        assert_eq!(v["device"], "test#2");
+        assert_eq!(v["temps"], json!([61, 58]));
+        assert_eq!(v["target"], 60);
+    }
+
+    #[test]
+    fn file_is_rotated_by_size() {
+        let c = conf("rotate", "csv", 2);
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", c.path.to_string_lossy(), i));
+        let mut h = History::open(&c).unwrap();
+        let ks = vec![fan()];
+
+        h.record(&ks, &[]);
+        let first = read(c.path.clone());
+        assert!(first.starts_with(CSV_HEADER));
+        assert_eq!(first.lines().count(), 2);
+
+        for _ in 0..3 {
+            h.size = 1024 * 1024 + 1;
+            h.record(&ks, &[]);
+        }
+        // Every rotation starts new file with header, only keep files are left
+        assert_eq!(read(c.path.clone()).lines().count(), 2);
+        assert_eq!(read(rotated(1)).lines().count(), 2);
+        assert!(rotated(2).exists());
+        assert!(!rotated(3).exists());
+
+        fs::remove_dir_all(c.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn json_history_is_continued() {
+        let c = conf("continue", "json", 1);
+        let ks = vec![fan()];
+        History::open(&c).unwrap().record(&ks, &[]);
+        History::open(&c).unwrap().record(&ks, &[]);
+        assert_eq!(read(c.path.clone()).lines().count(), 2);
+
+        fs::remove_dir_all(c.path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,21 +1,269 @@
 extern crate std;
 use std::collections::VecDeque;
-use std::time::SystemTime;
+use std::fmt::Debug;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
 
+use calibrate::Calibration;
+use clock::Clock;
+use clock::MonotonicClock;
+use core::Propeller;
 use core::Settings;
 use core::Thermometer;
 pub use core::Device;
 
 use dsys;
 use dnv;
+use pid::Pid;
+use curve::Curve;
 
 
+/// Temperature and PWM levels device should be kept at
 #[derive(Debug, Clone)]
-struct Jam {
-    pwm_ok: usize,
-    temp_ok: usize,
-    temp_hot: usize,
-    temp_crit: usize,
+pub struct Jam {
+    pub pwm_ok: usize,
+    pub pwm_min: usize,
+    pub pwm_max: usize,
+    pub temp_ok: usize,
+    pub temp_hot: usize,
+    pub temp_crit: usize,
+}
+
+/// Everything controller may need to decide about PWM
+#[derive(Debug)]
+pub struct Input<'a> {
+    /// Max temperature from all thermometers right now
+    pub temp: usize,
+    /// Recent temperatures from all thermometers, newest first
+    pub tlog: &'a VecDeque<usize>,
+    /// Max temperature in log
+    pub tlog_max: usize,
+    /// Log capacity
+    pub tlog_size: usize,
+    /// Current PWM speed
+    pub pwm: usize,
+    /// Seconds since last PWM change
+    pub idle: u64,
+    /// Seconds since previous decision, 0 for the first one
+    pub dt: f64,
+    pub jam: &'a Jam,
+}
+
+/// Target PWM speed and why it was chosen
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub pwm: usize,
+    pub reason: String,
+}
+
+/// Fan control strategy.
+/// Karlson feeds it with sampled temperatures and applies returned PWM
+pub trait Controller: Debug + Send {
+    fn decide(&mut self, input: &Input) -> Decision;
+
+    /// Apply new settings without resetting controller state
+    fn configure(&mut self, set: &Settings);
+
+    fn box_clone(&self) -> Box<dyn Controller>;
+}
+
+impl Clone for Box<dyn Controller> {
+    fn clone(&self) -> Box<dyn Controller> {
+        self.box_clone()
+    }
+}
+
+/// Create controller for strategy from settings
+pub fn controller_from(s: &Settings) -> Box<dyn Controller> {
+    match s.strategy.as_ref() {
+        "heuristic" => Box::new(Heuristic::new(s)),
+        "pid" => Box::new(Pid::new(s)),
+        "curve" if !s.curve.is_empty() => Box::new(Curve::new(s)),
+        "curve" => {
+            println!("ERROR curve strategy has no curve points, heuristic is used");
+            Box::new(Heuristic::new(s))
+        }
+        other => {
+            println!("ERROR unknown strategy {}, heuristic is used", other);
+            Box::new(Heuristic::new(s))
+        }
+    }
+}
+
+/// Fan is too slow if it spins below this percent of expected RPM
+pub const STALL_PERCENT: usize = 30;
+
+/// Heuristic slows fan down only if its speed was not changed for this time
+pub const DOWN_IDLE_SEC: u64 = 120;
+
+/// Default karlson strategy.
+/// Keep pwm_ok between temp_ok and temp_hot, slowly decrease fan below temp_ok
+/// until temperature stops falling, and increase fan above temp_hot.
+#[derive(Debug, Clone)]
+pub struct Heuristic {
+    pwm_up: isize,
+    pwm_down: isize,
+}
+
+impl Heuristic {
+    pub fn new(s: &Settings) -> Heuristic {
+        Heuristic {
+            pwm_up: s.pwm_step_up,
+            pwm_down: s.pwm_step_down,
+        }
+    }
+
+    /// Check if temperature in log decreasing
+    /// True if recent teperature is greater on 2C then oldest one
+    fn temp_decreasing(tlog: &VecDeque<usize>) -> bool {
+        let mut prev_temp: usize = 100;
+        let mut start_temp: usize = 100;
+        for (i, t) in tlog.iter().enumerate() {
+            if t > &prev_temp {
+                return false;
+            }
+            prev_temp = *t;
+            if i == 0 {
+                start_temp = *t;
+            }
+        }
+
+        prev_temp - start_temp > 2
+    }
+
+    /// Check if temperature in log increasing
+    /// True if oldest teperature is greater on 2C then recent one
+    fn temp_increasing(tlog: &VecDeque<usize>) -> bool {
+        let mut up = false;
+        let mut prev_temp: usize = 0;
+        let mut last_temp: usize = 0;
+        for (i, t) in tlog.iter().enumerate() {
+            if t < &prev_temp {
+                up = false;
+            }
+            prev_temp = *t;
+            if i == 0 {
+                last_temp = *t;
+            }
+        }
+
+        if up { last_temp - prev_temp > 2 } else { false }
+    }
+
+    /// Check if PWM speed is near provided value within delta range.
+    /// Return -1 if PWM less than range 0 - within range 1 - greater than range
+    fn pwm_near(pwm: isize, val: usize, delta_up: isize) -> isize {
+        if pwm < val as isize {
+            -1
+        } else if pwm < (val as isize) + delta_up {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl Controller for Heuristic {
+    fn decide(&mut self, inp: &Input) -> Decision {
+        let jam = inp.jam;
+        let pwm_now = inp.pwm as isize;
+        let pdown = self.pwm_down;
+        let pup = self.pwm_up;
+        let mut pwm = pwm_now;
+        let mut reason = "ok";
+
+        if inp.temp <= jam.temp_ok {
+            // Not hot at all. Only decrease temp here
+            if inp.tlog_size == 1 {
+                // Single temperature input
+                if inp.idle > DOWN_IDLE_SEC && Heuristic::temp_decreasing(inp.tlog) {
+                    pwm = pwm_now - pdown;
+                    reason = "cool and temperature decreasing";
+                }
+
+                if Heuristic::temp_increasing(inp.tlog) &&
+                    Heuristic::pwm_near(pwm, jam.pwm_ok, pup) < 0
+                {
+                    pwm = pwm_now + pup;
+                    reason = "cool but temperature increasing";
+                }
+            } else {
+                // For devices with many temperature inputs
+                if inp.idle > DOWN_IDLE_SEC && jam.temp_ok as isize - inp.tlog_max as isize > 2 {
+                    pwm = pwm_now - pdown;
+                    reason = "cool for a while";
+                }
+            }
+        } else if inp.temp > jam.temp_ok && inp.temp < jam.temp_hot {
+            // In this interval JUST normalize pwm up to OK level
+            if Heuristic::pwm_near(pwm, jam.pwm_ok, pup) < 0 {
+                pwm = pwm_now + pup;
+                reason = "warm, normalize up to pwm_ok";
+            }
+            if Heuristic::pwm_near(pwm, jam.pwm_ok, pup) > 0 &&
+                jam.temp_hot as isize - inp.tlog_max as isize > 1
+            {
+                pwm = pwm_now - pdown;
+                reason = "warm, normalize down to pwm_ok";
+            }
+        } else {
+            // Hot temp increase pwm only
+            if Heuristic::pwm_near(pwm, jam.pwm_ok, pup) < 0 {
+                // Just in case
+                pwm = jam.pwm_ok as isize + pup * 4;
+            } else {
+                pwm = pwm_now + pup * 2;
+            }
+            reason = "hot";
+        }
+
+        Decision {
+            pwm: pwm.max(0) as usize,
+            reason: String::from(reason),
+        }
+    }
+
+    fn configure(&mut self, set: &Settings) {
+        self.pwm_up = set.pwm_step_up;
+        self.pwm_down = set.pwm_step_down;
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new((*self).clone())
+    }
+}
+
+/// When temperature can not be read
+#[derive(Debug, Clone)]
+struct Failsafe {
+    /// Consecutive failed reads limit
+    reads: usize,
+    /// Seconds without temperature data limit
+    time: u64,
+    pwm: usize,
+}
+
+/// Manual control requested at runtime
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hold {
+    /// Karlson controls the fan
+    Auto,
+    /// Fan speed is kept, only critical temperature and fail-safe change it
+    Paused,
+    /// Fan speed is set by user until deadline
+    Forced(usize, Instant),
+    /// Fan control is returned to driver, karlson only reads temperature
+    Released,
+}
+
+/// Event counters since daemon start
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    pub pwm_changes: u64,
+    /// Failed PWM reads and writes
+    pub pwm_errors: u64,
+    pub temp_errors: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -24,31 +272,132 @@ pub struct Karlson {
     // p: Box<Propeller>,
     // ts: Vec<Box<Thermometer>>,
     pub dev: Device,
-    jam: Jam,
+    /// Settings device was configured with
+    set: Settings,
+    pub jam: Jam,
+    ctl: Box<dyn Controller>,
     pub pwm_speed: usize,
-    pwm_up: isize,
-    pwm_down: isize,
+    /// PWM speed read at the start of last spin
+    pub pwm_before: usize,
+    /// PWM speed chosen on last spin
+    pub pwm_target: usize,
+    pub counters: Counters,
+    /// Why PWM speed was chosen on last spin
+    pub reason: String,
+    pub hold: Hold,
+    /// Last temperatures of all thermometers
+    pub temps: Vec<usize>,
     tlog: VecDeque<usize>,
     tlog_size: usize,
-    updated: SystemTime,
+    pwm_exit: Option<usize>,
+    fail: Failsafe,
+    /// Consecutive failed temperature reads
+    temp_fails: usize,
+    /// Last time when temperature was read
+    temp_updated: Instant,
+    /// True when temperature is unknown and fan runs at fail-safe speed
+    pub sensor_fault: bool,
+    /// Last tachometer reading, None if fan does not report it
+    pub rpm: Option<usize>,
+    /// True when fan does not spin as commanded for stall_time
+    pub stalled: bool,
+    /// When fan was first seen spinning too slow
+    slow_since: Option<Instant>,
+    /// Measured PWM to RPM table of fan, see calibrate.rs
+    pub calibration: Option<Calibration>,
+    /// True when fan is stopped in zero RPM mode
+    pub fan_off: bool,
+    /// When temperature dropped below zero_rpm_temp
+    cool_since: Option<Instant>,
+    /// Fan starting from 0 runs at full speed until this time, then it is set to the speed
+    /// it was started for
+    kick_until: Option<(Instant, usize)>,
+    updated: Instant,
+    /// Last time controller was asked for PWM
+    decided: Option<Instant>,
+    clock: Box<dyn Clock>,
 }
 
-pub fn list_devices() -> Vec<Device> {
+/// Step by step karlson creation for discovered devices and custom hardware.
+/// Sys devices get propeller and thermometers from sysfs on build
+#[derive(Debug, Clone)]
+pub struct KarlsonBuilder {
+    dev: Device,
+    set: Settings,
+    clock: Box<dyn Clock>,
+}
+
+impl KarlsonBuilder {
+    /// Custom device without hardware, add propeller and thermometers to it
+    pub fn new(dev_type: &str, id: i32, name: &str) -> KarlsonBuilder {
+        KarlsonBuilder::from(&Device::new(dev_type, id, name))
+    }
+
+    /// Device found by list_devices
+    pub fn from(dev: &Device) -> KarlsonBuilder {
+        KarlsonBuilder {
+            dev: dev.clone(),
+            set: Settings::default(),
+            clock: Box::new(MonotonicClock),
+        }
+    }
+
+    pub fn settings(mut self, s: &Settings) -> KarlsonBuilder {
+        self.set = s.clone();
+        self
+    }
+
+    pub fn propeller(mut self, p: Box<dyn Propeller>) -> KarlsonBuilder {
+        self.dev.propeller = Some(p);
+        self
+    }
+
+    pub fn thermometer(mut self, t: Box<dyn Thermometer>) -> KarlsonBuilder {
+        self.dev.termometers.push(t);
+        self
+    }
+
+    pub fn clock(mut self, c: Box<dyn Clock>) -> KarlsonBuilder {
+        self.clock = c;
+        self
+    }
+
+    /// Create karlson, default fan speed is set right away
+    pub fn build(self) -> Karlson {
+        Karlson::with_clock(&self.dev, &self.set, self.clock)
+    }
+}
+
+/// List all available devices, sys devices are looked up under provided sysfs root
+pub fn list_devices(sys_root: &Path) -> Vec<Device> {
     let mut res: Vec<Device> = Vec::new();
 
-    res.extend(dsys::sys_devices());
+    res.extend(dsys::sys_devices(sys_root));
     res.extend(dnv::nv_devices());
     res
 }
 
 impl Karlson {
     pub fn new(dev: &Device, s: &Settings) -> Karlson {
-        let device = match dev.dev_type.as_ref() {
+        Karlson::with_clock(dev, s, Box::new(MonotonicClock))
+    }
+
+    pub fn builder(dev: &Device) -> KarlsonBuilder {
+        KarlsonBuilder::from(dev)
+    }
+
+    /// Create karlson with provided time source, fake clock makes timing deterministic
+    pub fn with_clock(dev: &Device, s: &Settings, clock: Box<dyn Clock>) -> Karlson {
+        let now = clock.now();
+        let mut device = match dev.dev_type.as_ref() {
             "sys" => dsys::sys_device_update(dev, s),
             _ => dev.clone(),
         };
+        if let Some(ref mut p) = device.propeller {
+            p.configure(s);
+        }
 
-        let temps = if device.termometers.len() > 0 {
+        let temps = if !device.termometers.is_empty() {
             device.termometers.len()
         } else {
             1
@@ -62,62 +411,243 @@ impl Karlson {
 
         Karlson {
             dev: device,
+            set: s.clone(),
             pwm_speed: dp.as_ref().map_or(
                 0,
                 |p| p.pwm_set(s.pwm_ok).unwrap_or(speed),
             ),
+            pwm_before: speed,
+            pwm_target: s.pwm_ok,
+            counters: Counters::default(),
+            reason: String::from("start at pwm_ok"),
+            hold: Hold::Auto,
+            temps: Vec::new(),
             tlog: VecDeque::new(),
             tlog_size: s.queue_size * temps,
-            pwm_up: s.pwm_step_up,
-            pwm_down: s.pwm_step_down,
+            ctl: controller_from(s),
             jam: Jam {
                 pwm_ok: s.pwm_ok,
+                pwm_min: s.pwm_min,
+                pwm_max: s.pwm_max,
                 temp_ok: s.temp_ok,
                 temp_hot: s.temp_hot,
                 temp_crit: s.temp_crit,
             },
-            updated: SystemTime::now(),
+            pwm_exit: s.pwm_exit,
+            fail: Failsafe {
+                reads: s.fail_reads,
+                time: s.fail_time,
+                pwm: s.pwm_fail,
+            },
+            temp_fails: 0,
+            temp_updated: now,
+            sensor_fault: false,
+            rpm: None,
+            stalled: false,
+            slow_since: None,
+            calibration: None,
+            fan_off: false,
+            cool_since: None,
+            kick_until: None,
+            updated: now,
+            decided: None,
+            clock,
         }
     }
 
     /// Create hybrid device with many inputs and custom propeller
     pub fn new_device(id: i32, s: &Settings) -> Karlson {
-        let mut terms: Vec<Box<Thermometer>> = Vec::new();
+        let mut terms: Vec<Box<dyn Thermometer>> = Vec::new();
 
         terms.extend(
             s.sys_temp_files
                 .clone()
                 .into_iter()
-                .filter_map(|p| dsys::sys_termometer_from(&p).ok())
-                .collect::<Vec<Box<Thermometer>>>(),
+                .filter_map(|p| {
+                    dsys::sys_termometer_from(&dsys::sys_path(&s.sys_root, &p)).ok()
+                })
+                .collect::<Vec<Box<dyn Thermometer>>>(),
         );
 
         terms.extend(
             s.nv_temp_ids
                 .clone()
                 .into_iter()
-                .filter_map(|p| dnv::nv_termometer_from(p as i32).ok())
-                .collect::<Vec<Box<Thermometer>>>(),
+                .filter_map(|p| match dnv::nv_termometer_from(&p) {
+                    Ok(t) => Some(t),
+                    Err(e) => {
+                        println!("ERROR {}", e);
+                        None
+                    }
+                })
+                .collect::<Vec<Box<dyn Thermometer>>>(),
         );
 
         Karlson::new(
             &Device {
-                id: id,
+                id,
                 dev_type: String::from("dev"),
                 name: s.name.clone().unwrap_or_default(),
+                path: None,
+                pci: None,
+                uuid: None,
+                driver: None,
+                card: None,
                 termometers: terms,
-                propeller: dsys::sys_propeller_from(&s.sys_pwm_file, s).ok(),
+                propeller: dsys::sys_propeller_from(
+                    &dsys::sys_path(&s.sys_root, &s.sys_pwm_file),
+                    s,
+                ).ok(),
             },
             s,
         )
     }
 
 
-    /// Return (max_temp, max_temp_from_log)
-    fn load_temp(&mut self) -> (usize, usize) {
-        let temps: Vec<usize> = self.dev.termometers.iter().map(|t| t.temp()).collect();
+    pub fn settings(&self) -> &Settings {
+        &self.set
+    }
+
+    /// Current time of karlson clock
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Stop changing fan speed, critical temperature and fail-safe still apply
+    pub fn pause(&mut self) {
+        self.hold = Hold::Paused;
+        self.reason = String::from("paused by user");
+        println!("{}#{} control paused -> {}", self.dev.dev_type, self.dev.id, self.dev.name);
+    }
+
+    /// Return to automatic control, also after force or release
+    pub fn resume(&mut self) {
+        self.hold = Hold::Auto;
+        self.reason = String::from("resumed by user");
+        println!("{}#{} control resumed -> {}", self.dev.dev_type, self.dev.id, self.dev.name);
+    }
+
+    /// Set fan speed for provided time, automatic control resumes after it
+    pub fn force(&mut self, pwm: usize, time: Duration) -> Result<usize, String> {
+        let pwm = pwm.min(self.jam.pwm_max);
+        let prop = self.dev.propeller.as_ref().ok_or("no propeller")?;
+        let p = prop.pwm_set(pwm)?;
+        self.pwm_speed = p;
+        self.pwm_target = pwm;
+        self.counters.pwm_changes += 1;
+        self.updated = self.clock.now();
+        self.hold = Hold::Forced(pwm, self.updated + time);
+        self.kick_until = None;
+        self.reason = String::from("forced by user");
+        println!(
+            "{}#{} PWM forced to {}% for {} sec -> {}",
+            self.dev.dev_type,
+            self.dev.id,
+            p,
+            time.as_secs(),
+            self.dev.name
+        );
+        Ok(p)
+    }
+
+    /// Hand fan back to driver until resume
+    pub fn release_control(&mut self) {
+        self.kick_until = None;
+        self.release();
+        self.hold = Hold::Released;
+        self.reason = String::from("released to driver");
+    }
+
+    /// Check if new settings point to other PWM or temperature inputs,
+    /// such device can not be configured in place
+    pub fn inputs_changed(&self, s: &Settings) -> bool {
+        self.set.sys_root != s.sys_root || self.set.sys_pwm_file != s.sys_pwm_file ||
+            self.set.sys_temp_files != s.sys_temp_files ||
+            self.set.nv_temp_ids != s.nv_temp_ids
+    }
+
+    /// Apply new settings keeping current PWM speed and temperature log.
+    /// Return false if settings were not changed
+    pub fn configure(&mut self, s: &Settings) -> bool {
+        if self.set == *s {
+            return false;
+        }
+
+        if self.set.strategy != s.strategy {
+            self.ctl = controller_from(s);
+        } else {
+            self.ctl.configure(s);
+        }
+        if let Some(ref mut p) = self.dev.propeller {
+            p.configure(s);
+        }
+
+        self.jam = Jam {
+            pwm_ok: s.pwm_ok,
+            pwm_min: s.pwm_min,
+            pwm_max: s.pwm_max,
+            temp_ok: s.temp_ok,
+            temp_hot: s.temp_hot,
+            temp_crit: s.temp_crit,
+        };
+        self.tlog_size = s.queue_size * self.dev.termometers.len().max(1);
+        self.tlog.truncate(self.tlog_size);
+        self.pwm_exit = s.pwm_exit;
+        let wake = s.zero_rpm_temp.is_none() && self.fan_off;
+        if s.zero_rpm_temp.is_none() {
+            self.cool_since = None;
+        }
+        self.fail = Failsafe {
+            reads: s.fail_reads,
+            time: s.fail_time,
+            pwm: s.pwm_fail,
+        };
+        self.set = s.clone();
+        if wake {
+            // Stopped fan must not wait for controller, it may decide to stay at 0.
+            // It is still fan_off here, so it is kicked like in zero rpm mode
+            let temp = self.tlog.front().cloned();
+            let pwm = self.pwm_target.max(if s.pwm_min > 0 { s.pwm_min } else { s.pwm_ok });
+            self.reason = String::from("zero rpm disabled");
+            self.pwm_update(pwm as isize, s.pwm_max, temp, self.clock.now());
+            self.fan_off = false;
+        }
+        true
+    }
+
+    /// Return (max_temp, max_temp_from_log) of thermometers that could be read,
+    /// failed ones are reported and counted.
+    /// Error if there is no valid reading at all
+    fn load_temp(&mut self) -> Result<(usize, usize), String> {
+        if self.dev.termometers.is_empty() {
+            return Err(String::from("No thermometers"));
+        }
+
+        let mut temps: Vec<usize> = Vec::new();
+        let mut errs: Vec<String> = Vec::new();
+        for t in self.dev.termometers.iter() {
+            match t.temp() {
+                Ok(0) => errs.push(format!("Temperature is 0C {:?}", t)),
+                Ok(v) => temps.push(v),
+                Err(e) => errs.push(e),
+            }
+        }
+        if temps.is_empty() {
+            return Err(errs.join(", "));
+        }
+        for e in errs {
+            self.counters.temp_errors += 1;
+            println!(
+                "ERROR {}#{} can not read temperature, other thermometers are used {} -> {}",
+                self.dev.dev_type,
+                self.dev.id,
+                e,
+                self.dev.name
+            );
+        }
 
         let mut tmax = 0;
+        self.temps = temps.clone();
 
         for t in temps {
             if tmax < t {
@@ -130,155 +660,245 @@ impl Karlson {
             self.tlog.pop_back();
         }
 
-        if self.tlog.is_empty() {
-            return (0, 0);
+        let lmax: usize = *self.tlog.iter().max().unwrap_or(&0_usize);
+        Ok((tmax, lmax))
+    }
+
+    /// Count failed temperature read and switch to fail-safe speed if it fails for too long
+    fn temp_failed(&mut self, err: String, now: Instant) {
+        self.temp_fails += 1;
+        // Do not let controller count time without temperature data
+        self.decided = None;
+        let sec = now.saturating_duration_since(self.temp_updated).as_secs();
+
+        println!(
+            "ERROR {}#{} can not read temperature ({} times, {} sec) {} -> {}",
+            self.dev.dev_type,
+            self.dev.id,
+            self.temp_fails,
+            sec,
+            err,
+            self.dev.name
+        );
+
+        if self.temp_fails < self.fail.reads && sec < self.fail.time {
+            return;
         }
 
-        let lmax: usize = self.tlog.iter().max().unwrap_or(&(0 as usize)).clone();
-        return (tmax, lmax);
+        if !self.sensor_fault {
+            self.sensor_fault = true;
+            println!(
+                "FAULT {}#{} temperature is unknown, set fail-safe PWM {}% -> {}",
+                self.dev.dev_type,
+                self.dev.id,
+                self.fail.pwm,
+                self.dev.name
+            );
+        }
+        let pwm = self.fail.pwm as isize;
+        self.reason = String::from("fail-safe, temperature is unknown");
+        self.pwm_update(pwm, 100, None, now);
     }
 
-    /// Check if temperature in log decreasing
-    /// True if recent teperature is greater on 2C then oldest one
-    fn temp_decreasing(&self) -> bool {
-        let mut i = 0;
-        let mut prev_temp: usize = 100;
-        let mut start_temp: usize = 100;
-        for t in self.tlog.iter() {
-            if t > &prev_temp {
-                return false;
-            }
-            prev_temp = t.clone();
-            if i == 0 {
-                start_temp = t.clone();
-            }
-            i += 1;
+    /// RPM fan should have at current PWM speed, None if it is not known.
+    /// Calibrated table is more precise than linear rpm_max
+    fn expected_rpm(&self) -> Option<usize> {
+        match self.calibration {
+            Some(ref c) => c.rpm_at(self.pwm_speed),
+            None => self.set.rpm_max.map(|m| m * self.pwm_speed / 100),
         }
-
-        prev_temp - start_temp > 2
     }
 
-    /// Check if temperature in log increasing
-    /// True if oldest teperature is greater on 2C then recent one
-    fn temp_increasing(&self) -> bool {
-        let mut up = false;
-        let mut i = 0;
-        let mut prev_temp: usize = 0;
-        let mut last_temp: usize = 0;
-        for t in self.tlog.iter() {
-            if t < &prev_temp {
-                up = false;
+    /// Report fan that does not spin as commanded for stall_time
+    fn check_stall(&mut self, rpm: Option<usize>, now: Instant) {
+        self.rpm = rpm;
+        let slow = match rpm {
+            Some(r) if self.set.stall_time > 0 && self.pwm_speed > 0 => {
+                // Calibrated fan may be expected to stand still at low speed
+                match self.expected_rpm() {
+                    Some(e) => r * 100 < e * STALL_PERCENT,
+                    None => r == 0,
+                }
             }
-            prev_temp = t.clone();
-            if i == 0 {
-                last_temp = t.clone();
+            _ => false,
+        };
+
+        if !slow {
+            self.slow_since = None;
+            if self.stalled {
+                self.stalled = false;
+                println!(
+                    "{}#{} fan spins again {} RPM at {}% -> {}",
+                    self.dev.dev_type,
+                    self.dev.id,
+                    rpm.unwrap_or(0),
+                    self.pwm_speed,
+                    self.dev.name
+                );
             }
-            i += 1;
+            return;
         }
 
-        if up { last_temp - prev_temp > 2 } else { false }
-    }
-
-    fn adjust_pwm(&mut self, tmax: usize, tlog_max: usize) {
-        if tmax <= 0 {
+        let since = *self.slow_since.get_or_insert(now);
+        let sec = now.saturating_duration_since(since).as_secs();
+        if !self.stalled && sec >= self.set.stall_time {
+            self.stalled = true;
             println!(
-                "ERROR temparature is {}C for device {}#{}",
-                tmax,
+                "FAULT {}#{} fan is stalled, {} RPM (expected {}) at {}% for {} sec -> {}",
                 self.dev.dev_type,
-                self.dev.id
+                self.dev.id,
+                rpm.unwrap_or(0),
+                self.expected_rpm().map_or(String::from("> 0"), |e| e.to_string()),
+                self.pwm_speed,
+                sec,
+                self.dev.name
             );
-            return;
         }
+    }
 
-        let pwm_now = self.pwm_speed as isize;
-        let pdown = self.pwm_down;
-        let pup = self.pwm_up;
+    fn adjust_pwm(&mut self, tmax: usize, tlog_max: usize, now: Instant) {
+        let idle = now.saturating_duration_since(self.updated);
+        let dt = self.decided
+            .map(|d| now.saturating_duration_since(d).as_secs_f64())
+            .unwrap_or(0.0);
+        self.decided = Some(now);
 
-        if tmax <= self.jam.temp_ok {
-            // Not hot at all. Only decrease temp here
-            let duration = SystemTime::now().duration_since(self.updated);
-            if duration.is_ok() {
-                let sec = duration.unwrap().as_secs();
-                #[cfg(debug_assertions)]
-                {
-                    println!(
-                        "{}#{} TEMP:{}C ({}..{}) duration sec {} decreasing {} increasing {}",
-                        self.dev.dev_type,
-                        self.dev.id,
-                        tmax,
-                        self.jam.temp_ok,
-                        self.jam.temp_hot,
-                        sec,
-                        self.temp_decreasing(),
-                        self.temp_increasing()
-                    );
-                }
+        let d = self.ctl.decide(&Input {
+            temp: tmax,
+            tlog: &self.tlog,
+            tlog_max,
+            tlog_size: self.tlog_size,
+            pwm: self.pwm_speed,
+            idle: idle.as_secs(),
+            dt,
+            jam: &self.jam,
+        });
 
-                if self.tlog_size == 1 {
-                    // Single temperature input
-                    if sec > 120 && self.temp_decreasing() {
-                        self.pwm_update(pwm_now - pdown, tmax);
-                    }
+        #[cfg(debug_assertions)]
+        {
+            println!(
+                "{}#{} TEMP:{}C ({}..{}) decision {}% {}",
+                self.dev.dev_type,
+                self.dev.id,
+                tmax,
+                self.jam.temp_ok,
+                self.jam.temp_hot,
+                d.pwm,
+                d.reason
+            );
+        }
 
-                    if self.temp_increasing() && self.pwm_near(self.jam.pwm_ok, pup) < 0 {
-                        self.pwm_update(pwm_now + pup, tmax);
-                    }
-                } else {
-                    // For devices with many temperature inputs
-                    if sec > 120 && self.jam.temp_ok as isize - tlog_max as isize > 2 {
-                        self.pwm_update(pwm_now - pdown, tmax);
-                    }
-                }
-            } else {
-                println!("ERROR {}", duration.err().unwrap());
+        if tmax > self.jam.temp_crit {
+            // If super hot, just set PWM at max
+            self.reason = String::from("critical temperature");
+            self.pwm_update(100, 100, Some(tmax), now);
+            return;
+        }
+
+        if let Some((until, target)) = self.kick_until {
+            if now < until {
+                self.reason = format!("kick, starting fan to {}%", target);
+                return;
             }
-        } else if tmax > self.jam.temp_ok && tmax < self.jam.temp_hot {
-            // In this interval JUST normalize pwm up to OK level
-            if self.pwm_near(self.jam.pwm_ok, self.pwm_up) < 0 {
-                self.pwm_update(pwm_now + pup, tmax);
+            // Controller would take full speed of kick for its own decision
+            self.kick_until = None;
+            self.reason = String::from("kick done, settle at target");
+            let max = self.jam.pwm_max;
+            self.pwm_update(target as isize, max, Some(tmax), now);
+            return;
+        }
+
+        match self.hold {
+            Hold::Forced(pwm, until) if now < until => {
+                self.reason = String::from("forced by user");
+                let max = self.jam.pwm_max;
+                self.pwm_update(pwm as isize, max, Some(tmax), now);
             }
-            if self.pwm_near(self.jam.pwm_ok, self.pwm_up) > 0 &&
-                self.jam.temp_hot as isize - tlog_max as isize > 1
-            {
-                self.pwm_update(pwm_now - pdown, tmax);
+            Hold::Forced(..) => {
+                println!(
+                    "{}#{} forced PWM expired, control resumed -> {}",
+                    self.dev.dev_type,
+                    self.dev.id,
+                    self.dev.name
+                );
+                self.hold = Hold::Auto;
+                let d = self.zero_rpm(d, tmax, now);
+                self.reason = d.reason;
+                let max = self.jam.pwm_max;
+                self.pwm_update(d.pwm as isize, max, Some(tmax), now);
             }
-        } else {
-            // Hot temp increase pwm only
-            if self.pwm_near(self.jam.pwm_ok, self.pwm_up) < 0 {
-                // Just in case
-                let pwm_ok = self.jam.pwm_ok;
-                self.pwm_update(pwm_ok as isize + pup * 4, tmax);
-            } else {
-                self.pwm_update(pwm_now + pup * 2, tmax);
+            Hold::Paused => self.reason = String::from("paused by user"),
+            _ => {
+                let d = self.zero_rpm(d, tmax, now);
+                self.reason = d.reason;
+                let max = self.jam.pwm_max;
+                self.pwm_update(d.pwm as isize, max, Some(tmax), now);
             }
         }
+    }
 
-        if tmax > self.jam.temp_crit {
-            // If super hot, just set PWM at max
-            self.pwm_update(100, tmax);
+    /// Stop fan when temperature stays below zero_rpm_temp for zero_rpm_dwell seconds,
+    /// start it again above zero_rpm_temp + zero_rpm_hysteresis
+    fn zero_rpm(&mut self, d: Decision, temp: usize, now: Instant) -> Decision {
+        let off_temp = match self.set.zero_rpm_temp {
+            Some(t) => t,
+            None => return d,
+        };
+        let off = Decision {
+            pwm: 0,
+            reason: String::from("zero rpm, cool"),
+        };
+
+        if self.fan_off {
+            if temp < off_temp + self.set.zero_rpm_hysteresis {
+                return off;
+            }
+            self.fan_off = false;
+            self.cool_since = None;
+            println!(
+                "{}#{} fan starts, {}C is above zero_rpm_temp -> {}",
+                self.dev.dev_type,
+                self.dev.id,
+                temp,
+                self.dev.name
+            );
+            return Decision {
+                pwm: d.pwm.max(self.jam.pwm_min),
+                reason: String::from("zero rpm, warming up"),
+            };
         }
-    }
 
-    /// Check if current PWM speed is near provided value within delta range.
-    /// Return -1 if PWM less than range 0 - within range 1 - greater than range
-    fn pwm_near(&self, val: usize, delta_up: isize) -> isize {
-        if self.pwm_speed < val {
-            return -1;
-        } else if self.pwm_speed >= val && (self.pwm_speed as isize) < ((val as isize) + delta_up) {
-            return 0;
-        } else {
-            return 1;
+        if temp >= off_temp {
+            self.cool_since = None;
+            return d;
+        }
+        let since = *self.cool_since.get_or_insert(now);
+        if now.saturating_duration_since(since).as_secs() < self.set.zero_rpm_dwell {
+            return d;
         }
+        self.fan_off = true;
+        println!(
+            "{}#{} fan stops, {}C is below zero_rpm_temp for {} sec -> {}",
+            self.dev.dev_type,
+            self.dev.id,
+            temp,
+            self.set.zero_rpm_dwell,
+            self.dev.name
+        );
+        off
     }
 
-
-    fn pwm_update(&mut self, pwm: isize, temp: usize) {
+    /// Set fan speed limited by max, only critical temperature and fail-safe go above pwm_max.
+    /// Temperature is only logged, it is None when there is no valid reading
+    fn pwm_update(&mut self, pwm: isize, max: usize, temp: Option<usize>, now: Instant) {
+        let max = max.min(100);
         let pwm_val = if pwm > 0 {
-            if pwm > 100 { 100 } else { pwm as usize }
+            if pwm > max as isize { max } else { pwm as usize }
         } else {
             0
         };
-        let ref prop = &self.dev.propeller;
+        self.pwm_target = pwm_val;
+        let prop = &self.dev.propeller;
         if prop.is_none() {
             println!(
                 "ERROR can not file propeller for device #{} {}",
@@ -288,14 +908,25 @@ impl Karlson {
             return;
         }
         //  || pwm_val < 0
-        if pwm_val == self.pwm_speed as usize {
+        if pwm_val == self.pwm_speed {
             return;
         }
 
+        // Stopped fan may not start at low speed, it gets full speed first
+        // and settles at target on the first tick after kick_time
+        let kick = (self.set.zero_rpm_temp.is_some() || self.fan_off) && self.set.kick_time > 0 &&
+            self.pwm_speed == 0 && pwm_val < 100;
+        let pwm_val = if kick {
+            self.kick_until = Some((now + Duration::from_secs(self.set.kick_time), pwm_val));
+            self.reason = format!("kick, starting fan to {}%", pwm_val);
+            100
+        } else {
+            pwm_val
+        };
 
         match prop.as_ref().unwrap().pwm_set(pwm_val) {
             Ok(p) => {
-                self.updated = SystemTime::now();
+                self.updated = now;
                 // let updated = if p != self.pwm_speed { true } else { false };
                 let ud = if self.pwm_speed > pwm_val {
                     "DOWN"
@@ -305,24 +936,76 @@ impl Karlson {
                 self.pwm_speed = p;
                 // if updated {
                 println!(
-                    "{}#{} PWM {} to {}% temp {}C -> {}",
+                    "{}#{} PWM {} to {}% {}({}) -> {}",
                     self.dev.dev_type,
                     self.dev.id,
                     ud,
                     pwm_val,
-                    temp,
+                    temp.map_or(String::new(), |t| format!("temp {}C ", t)),
+                    self.reason,
                     self.dev.name
                 );
                 // }
+                self.counters.pwm_changes += 1;
             }
-            Err(e) => println!("ERROR {}", e),
+            Err(e) => {
+                self.counters.pwm_errors += 1;
+                println!("ERROR {}", e)
+            }
+        }
+    }
+
+
+    /// Stop controlling propeller.
+    /// Set exit PWM speed if configured, or return fan control back to driver
+    pub fn release(&mut self) {
+        let prop = match self.dev.propeller {
+            Some(ref p) => p,
+            None => return,
+        };
+
+        match self.pwm_exit {
+            Some(pwm) => match prop.pwm_leave(pwm) {
+                Ok(p) => {
+                    self.pwm_speed = p;
+                    println!(
+                        "{}#{} PWM set to exit speed {}% -> {}",
+                        self.dev.dev_type,
+                        self.dev.id,
+                        p,
+                        self.dev.name
+                    );
+                }
+                Err(e) => println!("ERROR {}", e),
+            },
+            None => self.restore(),
         }
     }
 
+    /// Return fan control back to driver, exit PWM speed is not used.
+    /// Karlson recreated for the same fan must see original driver mode, not manual one
+    pub fn restore(&mut self) {
+        let prop = match self.dev.propeller {
+            Some(ref p) => p,
+            None => return,
+        };
+
+        match prop.restore() {
+            Ok(r) => println!(
+                "{}#{} fan control restored {} -> {}",
+                self.dev.dev_type,
+                self.dev.id,
+                r,
+                self.dev.name
+            ),
+            Err(e) => println!("ERROR {}", e),
+        }
+    }
 
     /// Do some stuff to adjust Propeller speed
     /// This is only place where PWM speed updated before all logick run
     pub fn spin(&mut self) {
+        let now = self.clock.now();
         if self.dev.propeller.is_none() {
             println!(
                 "ERROR! Can not find propeller for device {}#{} {}",
@@ -336,6 +1019,8 @@ impl Karlson {
         match self.dev.propeller.as_ref().unwrap().pwm() {
             Ok(s) => self.pwm_speed = s,
             Err(e) => {
+                self.counters.pwm_errors += 1;
+                // Keep last known speed, temperature still has to be checked for fail-safe
                 println!(
                     "ERROR! Can not read PWM speed for device {}#{} {} -> {}",
                     self.dev.dev_type,
@@ -343,11 +1028,59 @@ impl Karlson {
                     self.dev.name,
                     e
                 );
-                return;
+            }
+        };
+        self.pwm_before = self.pwm_speed;
+
+        let rpm = match self.dev.propeller.as_ref().unwrap().rpm() {
+            Ok(r) => r,
+            Err(e) => {
+                println!(
+                    "ERROR {}#{} can not read fan RPM {} -> {}",
+                    self.dev.dev_type,
+                    self.dev.id,
+                    e,
+                    self.dev.name
+                );
+                None
+            }
+        };
+
+        if self.hold == Hold::Released {
+            // Driver controls the fan and may stop it on purpose, just keep state for status
+            self.rpm = rpm;
+            self.slow_since = None;
+            self.stalled = false;
+            if let Err(e) = self.load_temp() {
+                self.temps.clear();
+                self.counters.temp_errors += 1;
+                println!("ERROR {}#{} {} -> {}", self.dev.dev_type, self.dev.id, e, self.dev.name);
+            }
+            return;
+        }
+        self.check_stall(rpm, now);
+
+        let (tmax, tlog_max) = match self.load_temp() {
+            Ok(t) => t,
+            Err(e) => {
+                self.temps.clear();
+                self.counters.temp_errors += 1;
+                return self.temp_failed(e, now);
             }
         };
 
-        let (tmax, tlog_max) = self.load_temp();
+        self.temp_fails = 0;
+        self.temp_updated = now;
+        if self.sensor_fault {
+            self.sensor_fault = false;
+            println!(
+                "{}#{} temperature is back {}C -> {}",
+                self.dev.dev_type,
+                self.dev.id,
+                tmax,
+                self.dev.name
+            );
+        }
 
         //if cfg!(debug_assertions) {
         #[cfg(debug_assertions)]
@@ -365,6 +1098,254 @@ impl Karlson {
             );
         }
 
-        self.adjust_pwm(tmax, tlog_max);
+        self.adjust_pwm(tmax, tlog_max, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::FakeClock;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone)]
+    struct TestFan {
+        pwm: Rc<Cell<usize>>,
+        rpm: Rc<Cell<Option<usize>>>,
+    }
+
+    impl Propeller for TestFan {
+        fn pwm(&self) -> Result<usize, String> {
+            Ok(self.pwm.get())
+        }
+
+        fn pwm_set(&self, val: usize) -> Result<usize, String> {
+            self.pwm.set(val);
+            Ok(val)
+        }
+
+        fn box_clone(&self) -> Box<dyn Propeller> {
+            Box::new((*self).clone())
+        }
+
+        fn configure(&mut self, _set: &Settings) {}
+
+        fn restore(&self) -> Result<String, String> {
+            Ok(String::from("test"))
+        }
+
+        fn pwm_leave(&self, val: usize) -> Result<usize, String> {
+            self.pwm_set(val)
+        }
+
+        fn rpm(&self) -> Result<Option<usize>, String> {
+            Ok(self.rpm.get())
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestThermometer {
+        temp: Rc<Cell<usize>>,
+    }
+
+    impl Thermometer for TestThermometer {
+        fn temp(&self) -> Result<usize, String> {
+            Ok(self.temp.get())
+        }
+
+        fn box_clone(&self) -> Box<dyn Thermometer> {
+            Box::new((*self).clone())
+        }
+    }
+
+    /// Karlson with fake fan, thermometer and clock, all of them are moved from test
+    fn rig(temp: usize) -> (Karlson, FakeClock, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        rig_with(&Settings::default(), temp)
+    }
+
+    fn rig_with(set: &Settings, temp: usize) -> (Karlson, FakeClock, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let clock = FakeClock::new();
+        let pwm = Rc::new(Cell::new(0));
+        let t = Rc::new(Cell::new(temp));
+        let k = KarlsonBuilder::new("test", 0, "test")
+            .settings(set)
+            .propeller(Box::new(TestFan {
+                pwm: pwm.clone(),
+                rpm: Rc::new(Cell::new(None)),
+            }))
+            .thermometer(Box::new(TestThermometer { temp: t.clone() }))
+            .clock(Box::new(clock.clone()))
+            .build();
+        (k, clock, pwm, t)
+    }
+
+    #[test]
+    fn heuristic_slows_down_only_after_idle_time() {
+        let (mut k, clock, pwm, _) = rig(40);
+        assert_eq!(pwm.get(), 60);
+
+        k.spin();
+        clock.advance(Duration::from_secs(DOWN_IDLE_SEC));
+        k.spin();
+        assert_eq!(pwm.get(), 60);
+
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert_eq!(pwm.get(), 58);
+        assert_eq!(k.reason, "cool for a while");
+
+        // Speed was just changed, next step waits again
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert_eq!(pwm.get(), 58);
+    }
+
+    #[test]
+    fn forced_speed_expires() {
+        let (mut k, clock, pwm, _) = rig(70);
+        assert_eq!(k.force(90, Duration::from_secs(60)), Ok(90));
+        let until = k.now() + Duration::from_secs(60);
+
+        clock.advance(Duration::from_secs(55));
+        k.spin();
+        assert_eq!(pwm.get(), 90);
+        assert_eq!(k.hold, Hold::Forced(90, until));
+
+        clock.advance(Duration::from_secs(10));
+        k.spin();
+        assert_eq!(k.hold, Hold::Auto);
+        assert_eq!(pwm.get(), 88);
+    }
+
+    #[test]
+    fn forced_speed_is_limited_by_pwm_max() {
+        let (mut k, _, pwm, _) = rig(70);
+        k.configure(&Settings {
+            pwm_max: 80,
+            ..Settings::default()
+        });
+        assert_eq!(k.force(95, Duration::from_secs(60)), Ok(80));
+        assert_eq!(pwm.get(), 80);
+    }
+
+    #[test]
+    fn fail_safe_waits_for_all_thermometers() {
+        let (mut k, clock, pwm, t) = rig(70);
+        // Thermometer reading 0C is failed one
+        k.dev.termometers.push(Box::new(TestThermometer {
+            temp: Rc::new(Cell::new(0)),
+        }));
+
+        for _ in 0..5 {
+            clock.advance(Duration::from_secs(5));
+            k.spin();
+        }
+        assert!(!k.sensor_fault);
+        assert_eq!(k.temps, vec![70]);
+        assert_eq!(k.counters.temp_errors, 5);
+        assert_eq!(pwm.get(), 60);
+
+        t.set(0);
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(5));
+            k.spin();
+        }
+        assert!(k.sensor_fault);
+        assert_eq!(pwm.get(), 100);
+    }
+
+    #[test]
+    fn slow_fan_is_stalled_after_stall_time() {
+        let set = Settings {
+            rpm_max: Some(3000),
+            ..Settings::default()
+        };
+        let (mut k, clock, pwm, _) = rig_with(&set, 70);
+        let rpm = Rc::new(Cell::new(Some(300)));
+        k.dev.propeller = Some(Box::new(TestFan {
+            pwm: pwm.clone(),
+            rpm: rpm.clone(),
+        }));
+
+        // 300 RPM is below 30% of 1800 expected at 60%
+        k.spin();
+        clock.advance(Duration::from_secs(25));
+        k.spin();
+        assert!(!k.stalled);
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert!(k.stalled);
+        assert_eq!(k.rpm, Some(300));
+
+        rpm.set(Some(1700));
+        k.spin();
+        assert!(!k.stalled);
+
+        // Driver may stop released fan on purpose
+        rpm.set(Some(0));
+        k.release_control();
+        for _ in 0..10 {
+            clock.advance(Duration::from_secs(5));
+            k.spin();
+        }
+        assert!(!k.stalled);
+    }
+
+    /// Fan stopped by zero rpm mode after dwell time at 40C
+    fn stopped() -> (Karlson, FakeClock, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let set = Settings {
+            zero_rpm_temp: Some(45),
+            zero_rpm_dwell: 60,
+            ..Settings::default()
+        };
+        let (mut k, clock, pwm, t) = rig_with(&set, 40);
+        k.spin();
+        clock.advance(Duration::from_secs(65));
+        k.spin();
+        assert!(k.fan_off);
+        assert_eq!(pwm.get(), 0);
+        (k, clock, pwm, t)
+    }
+
+    #[test]
+    fn kicked_fan_settles_at_target() {
+        let (mut k, clock, pwm, t) = stopped();
+
+        t.set(55);
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        let target = k.pwm_target;
+        assert!((21..100).contains(&target));
+        assert_eq!(pwm.get(), 100);
+        assert_eq!(k.reason, format!("kick, starting fan to {}%", target));
+
+        clock.advance(Duration::from_secs(1));
+        k.spin();
+        assert_eq!(pwm.get(), 100);
+
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert_eq!(pwm.get(), target);
+        assert_eq!(k.reason, "kick done, settle at target");
+
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert_eq!(pwm.get(), target);
+    }
+
+    #[test]
+    fn fan_woken_by_configure_settles_at_target() {
+        let (mut k, clock, pwm, _) = stopped();
+
+        assert!(k.configure(&Settings::default()));
+        assert!(!k.fan_off);
+        assert_eq!(pwm.get(), 100);
+        assert_eq!(k.reason, "kick, starting fan to 21%");
+
+        clock.advance(Duration::from_secs(5));
+        k.spin();
+        assert_eq!(pwm.get(), 21);
+        assert_eq!(k.reason, "kick done, settle at target");
     }
 }
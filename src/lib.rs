@@ -0,0 +1,94 @@
+//! Karlson fan control library.
+//!
+//! Finds hwmon and Nvidia devices, reads their thermometers and keeps fan speed
+//! with one of control strategies. The `karlson` daemon is built on top of it,
+//! but the loop can be driven by any program:
+//!
+//! ```no_run
+//! extern crate karlson;
+//!
+//! use karlson::{Karlson, Settings};
+//! use std::path::Path;
+//! use std::thread;
+//! use std::time::Duration;
+//!
+//! fn main() {
+//!     let set = Settings {
+//!         strategy: String::from("pid"),
+//!         target_temp: Some(65),
+//!         ..Settings::default()
+//!     };
+//!
+//!     let mut fans: Vec<Karlson> = karlson::list_devices(Path::new("/sys"))
+//!         .iter()
+//!         .map(|d| Karlson::builder(d).settings(&set).build())
+//!         .collect();
+//!
+//!     for _ in 0..10 {
+//!         for k in &mut fans {
+//!             k.spin();
+//!         }
+//!         thread::sleep(Duration::from_secs(5));
+//!     }
+//!     for k in &mut fans {
+//!         k.release();
+//!     }
+//! }
+//! ```
+//!
+//! Custom hardware is supported by implementing [`Propeller`] and [`Thermometer`]
+//! and passing them to [`KarlsonBuilder`], [`FakeClock`] makes timing deterministic.
+
+extern crate libc;
+extern crate regex;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
+
+/// Device, settings and hardware traits
+pub mod core;
+/// Configuration file parsing and validation
+pub mod config;
+/// hwmon devices from sysfs
+pub mod dsys;
+/// Nvidia GPUs through nvidia-smi and nvidia-settings
+pub mod dnv;
+/// Unix signals for daemon loop
+pub mod signal;
+/// Unix control socket of running daemon
+pub mod control;
+/// Prometheus metrics endpoint
+pub mod metrics;
+/// Per-tick history file
+pub mod history;
+/// Monotonic and fake time sources
+pub mod clock;
+/// PID strategy
+pub mod pid;
+/// Fan curve strategy
+pub mod curve;
+/// Fan controller and heuristic strategy
+pub mod karlson;
+/// Offline thermal simulator
+pub mod sim;
+/// Replay of recorded history
+pub mod replay;
+/// Fan PWM to RPM calibration
+pub mod calibrate;
+/// Daemon loop, configuration reload and check
+pub mod daemon;
+
+pub use clock::Clock;
+pub use clock::FakeClock;
+pub use clock::MonotonicClock;
+pub use config::Config;
+pub use core::Device;
+pub use core::Propeller;
+pub use core::Settings;
+pub use core::Thermometer;
+pub use karlson::Controller;
+pub use karlson::Hold;
+pub use karlson::Karlson;
+pub use karlson::KarlsonBuilder;
+pub use karlson::controller_from;
+pub use karlson::list_devices;
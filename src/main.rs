@@ -1,99 +1,61 @@
-//#![allow(unstable)]
-//#![feature(collections)]
-
-extern crate regex;
-
-mod core;
-use core::Settings;
-use core::read_file;
-
-mod dsys;
-mod dnv;
-
-mod karlson;
-use karlson::Karlson;
-
-
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::path::Path;
-use std::path::PathBuf;
-use std::time::SystemTime;
-
 extern crate getopts;
+extern crate karlson;
+#[macro_use]
+extern crate serde_json;
+
+use karlson::Device;
+use karlson::Settings;
+use karlson::calibrate;
+use karlson::config::Config;
+use karlson::config::CALIBRATION;
+use karlson::config::SOCKET;
+use karlson::control;
+use karlson::daemon;
+use karlson::dsys;
+use karlson::replay;
+use karlson::signal;
+use karlson::sim;
+
 use getopts::Options;
 use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 
-extern crate toml;
-use toml::Value;
-//use toml::value::Table;
-
-
-fn settings_default(tconf: &Value) -> Settings {
-    if tconf.is_table() {
-        Settings::from(&tconf)
-    } else {
-        Settings::from(&Value::from(false))
-    }
-}
-
-/// Return (sys settings, nv settings)
-fn settings_propellers(
-    tconf: &Value,
-    setdef: &Settings,
-) -> (HashMap<i32, Settings>, HashMap<i32, Settings>) {
-
-    let cempty = Vec::new();
-    let mut scfg: HashMap<i32, Settings> = HashMap::new();
-    let mut nvcfg: HashMap<i32, Settings> = HashMap::new();
-
-    let props = tconf.get("propellers");
-    if props.is_none() {
-        return (scfg, nvcfg);
-    }
-
-    if !props.unwrap().is_array() {
-        println!("ERROR in config! Wrong blocks format for [[propellers]]");
-        return (scfg, nvcfg);
-    }
-
-    let ccfg = props.unwrap().as_array().unwrap_or(&cempty);
-
-    for c in ccfg {
-        if !c.is_table() || !c.as_table().unwrap().contains_key("idx") {
-            continue;
-        }
-        let idx = c["idx"].as_array().unwrap_or(&cempty);
-
-        for idv in idx {
-            if !idv.is_integer() {
-                continue;
-            }
-            let id = idv.as_integer().unwrap();
-            if id >= 0 {
-                let dev_set = Settings::from_with(&c, &setdef);
-                match dev_set.dev_type.as_ref() {
-                    "nv" => nvcfg.insert(id as i32, dev_set),
-                    _ => scfg.insert(id as i32, dev_set),
-                };
-            }
-        }
-    }
 
-    (scfg, nvcfg)
+/// Load configuration for commands reporting single error, all problems are joined by lines
+fn load_config(path: &Path, sysfs: Option<&str>) -> Result<Config, String> {
+    Config::load(path, sysfs).map_err(|errs| {
+        let msgs: Vec<String> = errs.iter().map(|e| e.to_string()).collect();
+        msgs.join("\n")
+    })
 }
 
-fn format_info(devs: &Vec<Karlson>) -> String {
-    let d: usize = 0;
+/// Format devices from status response like daemon::format_info
+fn format_status(devs: &[serde_json::Value]) -> String {
     let forms: Vec<String> = devs.iter()
-        .map(|it| {
+        .map(|d| {
+            let hold = match d["hold"].as_str().unwrap_or("auto") {
+                "auto" => String::new(),
+                "forced" => format!(" FORCED {}s", d["forced_sec"].as_u64().unwrap_or(0)),
+                h => format!(" {}", h.to_uppercase()),
+            };
             format!(
-                "  {}#{} {}C {}% :: {}",
-                it.dev.id,
-                it.dev.dev_type,
-                it.dev.termometers.iter().map(|t| t.temp()).max().unwrap_or(d),
-                it.pwm_speed,
-                it.dev.name,
+                "  {}#{} {}C {}%{}{}{}{}{} :: {} ({})",
+                d["id"],
+                d["type"].as_str().unwrap_or(""),
+                d["temps"]
+                    .as_array()
+                    .and_then(|t| t.iter().filter_map(|v| v.as_u64()).max())
+                    .unwrap_or(0),
+                d["pwm"],
+                d["rpm"].as_u64().map_or(String::new(), |r| format!(" {}rpm", r)),
+                if d["sensor_fault"].as_bool() == Some(true) { " SENSOR FAULT" } else { "" },
+                if d["stalled"].as_bool() == Some(true) { " FAN STALLED" } else { "" },
+                if d["fan_off"].as_bool() == Some(true) { " FAN OFF" } else { "" },
+                hold,
+                d["name"].as_str().unwrap_or(""),
+                d["reason"].as_str().unwrap_or("")
             )
         })
         .collect();
@@ -101,172 +63,218 @@ fn format_info(devs: &Vec<Karlson>) -> String {
     forms.join("\n")
 }
 
-fn extract_ids(tconf: &Value, property_name: &str) -> HashSet<i32> {
-    let empty_vec = Vec::new();
-
-    (match tconf.as_table() {
-         Some(t) => {
-             match t.get(property_name) {
-                 Some(i) => i.as_array().unwrap_or(&empty_vec),
-                 None => &empty_vec,
-             }
-         }
-         None => &empty_vec,
-     }).iter()
-        .filter_map(|v| v.as_integer())
-        .map(|v| v as i32)
-        .collect()
+/// Parse duration like 90s, 10m or 1h, plain number is seconds
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .map(|n| n * mult)
+        .map_err(|_| format!("Invalid duration {}, expected like 90s, 10m or 1h", s))
 }
 
-fn init_karlsons(tconf: &Value, set_def: &Settings) -> Vec<Karlson> {
-
-    let (sys_set, nv_set) = settings_propellers(tconf, &set_def);
-
-    let sys_ids = extract_ids(tconf, "idx");
-    let nv_ids = extract_ids(tconf, "nv_idx");
-
-    let mut karlsons: Vec<Karlson> = Vec::new();
-    let devs = karlson::list_devices();
-
-    #[cfg(debug_assertions)]
-    {
-        println!("PROPELLERS {:?}", devs);
-    }
-
-    for d in devs {
-        let skipit = match d.dev_type.as_ref() {
-            "nv" => !nv_ids.contains(&d.id),
-            _ => !sys_ids.contains(&d.id),
-        };
-        if skipit {
-            #[cfg(debug_assertions)]
-            {
-                println!("Skip device #{} {}", d.id, d.name);
+/// Send command to running daemon and print response
+fn run_client(cmd: &str, args: &[String], socket: &Path, json: bool, time: Option<String>) -> Result<(), String> {
+    let dev = || args.first().cloned().ok_or(format!("{} requires device", cmd));
+    let req = match cmd {
+        "status" | "rescan" => json!({"cmd": cmd}),
+        "pause" | "resume" | "release" => json!({"cmd": cmd, "dev": dev()?}),
+        _ => {
+            let pwm = args.get(1)
+                .and_then(|p| p.parse::<u64>().ok())
+                .ok_or("set requires device and pwm 0..100")?;
+            let mut req = json!({"cmd": cmd, "dev": dev()?, "pwm": pwm});
+            if let Some(t) = time {
+                req["sec"] = json!(parse_duration(&t)?);
             }
-            continue;
+            req
         }
+    };
 
-        let ns = match d.dev_type.as_ref() {
-            "nv" => nv_set.get(&d.id).unwrap_or(&set_def),
-            _ => sys_set.get(&d.id).unwrap_or(&set_def),
-        };
-
-        karlsons.push(Karlson::new(&d, &ns));
-
+    let resp = control::request(socket, &req)?;
+    if json {
+        println!("{}", resp);
+        return Ok(());
     }
 
-    if karlsons.is_empty() {
-        if !sys_ids.is_empty() {
-            println!("Allowed system devices ids {:?}", sys_ids);
-        }
-        if !nv_set.is_empty() {
-            println!("Allowed Nvidia devices ids {:?}", nv_ids);
-        }
+    match resp.get("devices").and_then(|d| d.as_array()) {
+        Some(devs) => println!("{}", format_status(devs)),
+        None => match resp.get("device") {
+            Some(d) => println!("{}", format_status(std::slice::from_ref(d))),
+            None => println!("OK"),
+        },
     }
-
-    karlsons
+    Ok(())
 }
 
-fn init_devices(tconf: &Value, set_def: &Settings) -> Vec<Karlson> {
-    let mut devices = Vec::<Karlson>::new();
-    let cempty = Vec::new();
-
-    let cfgdev = tconf.get("devices");
-
-    if cfgdev.is_none() {
-        println!("[[devices]] configuration is empty");
-        return devices;
-    }
-
-    if !cfgdev.unwrap().is_array() {
-        println!("ERROR in config! Wrong blocks format for [[devices]]");
-        return devices;
+/// Simulate top level settings of every config file and print comparison
+fn run_simulate(files: &[String], time: Option<String>) -> Result<(), String> {
+    if files.is_empty() {
+        return Err(String::from("simulate requires at least one SETTINGS.toml"));
     }
-    let dcfg = cfgdev.unwrap().as_array().unwrap_or(&cempty);
-    let mut id: i32 = 0;
-    for c in dcfg {
-        if !c.is_table() {
-            println!("ERROR! [[devices]] #{} block is not table", id);
-            continue;
-        }
-        let t = c.as_table().unwrap();
+    let seconds = parse_duration(&time.unwrap_or(String::from("1h")))?;
 
-        let has_sys = t.contains_key("sys_temp_input") && t["sys_temp_input"].is_array();
-        let has_nv = t.contains_key("nv_temp_input") && t["nv_temp_input"].is_array();
-
-        if !has_nv && !has_sys {
-            println!("ERROR can not add [[devices]] without temperature inputs");
-            continue;
-        }
-        let dev_set = Settings::from_with(&c, set_def);
-        devices.push(Karlson::new_device(id, &dev_set));
-        id += 1;
+    let mut reports = Vec::new();
+    for f in files {
+        let conf = load_config(Path::new(f), None)?;
+        println!("Simulating {} for {} sec", f, seconds);
+        reports.push((f, sim::simulate(&conf.defaults, &conf.simulate, seconds)));
     }
 
-    #[cfg(debug_assertions)]
-    {
-        println!("DEVICES {:?}", devices);
+    println!(
+        "\n{:<24} {:<10} {:>8} {:>10} {:>12} {:>8}",
+        "SETTINGS",
+        "STRATEGY",
+        "PEAK",
+        "ABOVE HOT",
+        "PWM CHANGES",
+        "AVG PWM"
+    );
+    for (f, r) in reports {
+        println!(
+            "{:<24} {:<10} {:>7.1}C {:>9}s {:>12} {:>7.1}%",
+            f,
+            r.strategy,
+            r.peak_temp,
+            r.above_hot,
+            r.pwm_changes,
+            r.avg_pwm
+        );
     }
-    devices
+    Ok(())
 }
 
+/// Feed recorded history through current code with settings from config
+fn run_replay(args: &[String], sysfs: Option<&str>) -> Result<(), String> {
+    let (conf_path, trace) = match (args.first(), args.get(1)) {
+        (Some(c), Some(t)) => (Path::new(c), Path::new(t)),
+        _ => return Err(String::from("replay requires SETTINGS.toml and TRACE")),
+    };
+    let conf = load_config(conf_path, sysfs)?;
+    let samples = replay::load(trace)?;
 
-fn loop_daemon(mut karlsons: Vec<Karlson>, mut devices: Vec<Karlson>) {
-    let mut t = SystemTime::now();
-    let mut start = true;
-    loop {
-        if karlsons.is_empty() && devices.is_empty() {
-            println!("(X_X) No devices was added to service. Just do nothing and sleep!");
-            std::thread::sleep(std::time::Duration::from_secs(10));
-        }
-
-        for d in &mut devices {
-            d.spin();
-        }
+    let diffs = replay::replay(&conf, &samples, args.get(2).map(String::as_ref))?;
+    println!("{} decisions differ from recorded", diffs);
+    Ok(())
+}
 
-        for k in &mut karlsons {
-            k.spin();
-        }
+/// Measure fan of sys device and save its PWM to RPM table for daemon
+fn run_calibrate(
+    args: &[String],
+    sysfs: Option<&str>,
+    socket: Option<String>,
+    settle: Option<String>,
+) -> Result<(), String> {
+    let dev_arg = args.first().ok_or("calibrate requires DEVICE")?;
+    let conf = match args.get(1) {
+        Some(c) => Some(load_config(Path::new(c), sysfs)?),
+        None => None,
+    };
+    let defaults = Settings {
+        sys_root: PathBuf::from(sysfs.unwrap_or(dsys::SYS_ROOT)),
+        ..Settings::default()
+    };
+    let settle = parse_duration(&settle.unwrap_or(String::from("10s")))?;
+
+    let sys_root = conf.as_ref().map_or(&defaults.sys_root, |c| &c.defaults.sys_root);
+    let dev = karlson::list_devices(sys_root)
+        .into_iter()
+        .find(|d| format!("{}#{}", d.dev_type, d.id) == *dev_arg || d.matches(dev_arg))
+        .ok_or(format!("Device {} is not found", dev_arg))?;
+    let set = conf.as_ref().map_or(&defaults, |c| c.settings_for(&dev));
+
+    let socket = match (socket, conf.as_ref()) {
+        (Some(s), _) => Some(PathBuf::from(s)),
+        (None, Some(c)) => c.socket.clone(),
+        (None, None) => Some(PathBuf::from(SOCKET)),
+    };
+    if let Some(s) = socket {
+        calibrate::check_daemon(&s, &dev)?;
+    }
 
-        for _ in 0..10 {
-            std::thread::sleep(std::time::Duration::from_millis(500));
-        }
+    signal::listen();
+    println!(
+        "Calibrating {}#{} {}, fan will stop, abort is at {}C",
+        dev.dev_type,
+        dev.id,
+        dev.name,
+        set.temp_crit.saturating_sub(calibrate::CRIT_MARGIN)
+    );
+    let cal = calibrate::calibrate(&dev, set, std::time::Duration::from_secs(settle))?;
 
-        let n = SystemTime::now();
-        let d = n.duration_since(t).ok().map(|it| it.as_secs()).unwrap_or(0);
+    println!("\n{:>5} {:>6}", "PWM", "RPM");
+    for &(pwm, rpm) in &cal.rpm {
+        println!("{:>4}% {:>6}", pwm, rpm);
+    }
+    println!("\nFan keeps spinning down to {}%, stopped fan starts at {}%", cal.spin_min, cal.start_min);
+    println!("Suggested pwm_min = {}", cal.pwm_min());
 
-        if d > 120 || start {
-            start = false;
-            t = n;
-            if !karlsons.is_empty() {
-                println!("PROPELLERS:\n{}", format_info(&karlsons));
-            }
-            if !devices.is_empty() {
-                println!("DEVICES:\n{}", format_info(&devices));
-            }
+    let path = match conf {
+        Some(ref c) => c.calibration.clone(),
+        None => Some(PathBuf::from(CALIBRATION)),
+    };
+    match path {
+        Some(p) => {
+            calibrate::save(&p, &dev, set, &cal)?;
+            println!("Saved to {}, daemon loads it on start or SIGHUP", p.to_string_lossy());
         }
+        None => println!("WARNING calibration is disabled in settings, result is not saved"),
     }
+    Ok(())
 }
 
-fn run_daemon(tconf: &Value) {
-    let set_def = settings_default(tconf);
-
-    let karlsons = init_karlsons(tconf, &set_def);
-    let devices = init_devices(tconf, &set_def);
+/// Current fan speed and RPM, fan with 0 RPM at non zero speed is stalled
+fn format_fan(d: &Device, sys_root: &Path) -> String {
+    let set = Settings {
+        sys_root: sys_root.to_path_buf(),
+        ..Settings::default()
+    };
+    let prop = match (d.dev_type.as_ref(), &d.path) {
+        ("sys", Some(p)) => dsys::sys_propeller_from(&p.join(&set.sys_pwm_file), &set).ok(),
+        _ => d.propeller.clone(),
+    };
+    let p = match prop {
+        Some(p) => p,
+        None => return String::new(),
+    };
 
-    loop_daemon(karlsons, devices);
+    let pwm = p.pwm().ok();
+    let rpm = p.rpm().ok().flatten();
+    let mut res = pwm.map_or(String::new(), |v| format!(" fan={}%", v));
+    if let Some(r) = rpm {
+        res.push_str(&format!(" rpm={}", r));
+        if r == 0 && pwm.unwrap_or(0) > 0 {
+            res.push_str(" STALLED");
+        }
+    }
+    res
 }
 
-fn print_devices() {
-    let list = karlson::list_devices();
+fn print_devices(sys_root: &Path) {
+    let list = karlson::list_devices(sys_root);
     for d in list {
-        println!("{}#{} {}", d.dev_type, d.id, d.name)
+        println!("{}#{} {} {}{}", d.dev_type, d.id, d.name, d.ident(), format_fan(&d, sys_root))
     }
 }
 
 
 fn print_help(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]", program);
+    let brief = format!(
+        "Usage: {p} FILE [options]\n       \
+         {p} check SETTINGS.toml [options]\n       \
+         {p} status [--json]\n       \
+         {p} set DEVICE PWM [--for 10m]\n       \
+         {p} pause|resume|release DEVICE\n       \
+         {p} rescan\n       \
+         {p} simulate SETTINGS.toml... [--for 1h]\n       \
+         {p} replay SETTINGS.toml TRACE [DEVICE]\n       \
+         {p} calibrate DEVICE [SETTINGS.toml] [--settle 10s]\n\n\
+         DEVICE is type#id like sys#0 or nv#1, GPU UUID or PCI slot",
+        p = program
+    );
     print!("{}", opts.usage(&brief));
 }
 
@@ -278,33 +286,138 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("l", "list", "list available devices with pwm1 interface");
     opts.optflag("h", "help", "print this help menu");
+    opts.optopt(
+        "s",
+        "sysfs",
+        "sysfs root directory, overrides sysfs from settings (default /sys)",
+        "DIR",
+    );
     opts.optopt(
         "d",
         "daemon",
         "run daemon with settings from file",
         "SETTINGS.toml",
     );
+    opts.optopt(
+        "",
+        "socket",
+        &format!("daemon control socket (default {})", SOCKET),
+        "PATH",
+    );
+    opts.optflag("", "json", "print daemon response as JSON");
+    opts.optopt(
+        "",
+        "for",
+        "how long forced fan speed is kept (default 10m) or simulation runs (default 1h)",
+        "TIME",
+    );
+    opts.optopt(
+        "",
+        "settle",
+        "how long fan settles on every calibration step (default 10s)",
+        "TIME",
+    );
 
-
-    let matches;
-    match opts.parse(&args[1..]) {
-        Ok(m) => {
-            matches = m;
-        }
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
         Err(f) => {
-            println!("ERROR: {}\n", f.to_string());
+            println!("ERROR: {}\n", f);
             print_help(&program, opts);
             return;
         }
     };
 
+    let sysfs = matches.opt_str("s");
+
     if matches.opt_present("h") {
         print_help(&program, opts);
         return;
     }
 
     if matches.opt_present("l") {
-        print_devices();
+        print_devices(Path::new(sysfs.as_deref().unwrap_or(dsys::SYS_ROOT)));
+        return;
+    }
+
+    // CHECK
+    if matches.free.first().map(String::as_ref) == Some("check") {
+        let toml_path = match matches.free.get(1) {
+            Some(p) => PathBuf::from(p),
+            None => return print_help(&program, opts),
+        };
+
+        let problems = match Config::load(&toml_path, sysfs.as_deref()) {
+            Ok(conf) => daemon::check(&conf),
+            Err(errs) => {
+                for e in &errs {
+                    println!("ERROR {}", e);
+                }
+                errs.len()
+            }
+        };
+        if problems > 0 {
+            println!("{} problems found", problems);
+            process::exit(1);
+        }
+        println!("Configuration is OK");
+        return;
+    }
+
+    // SIMULATE
+    if matches.free.first().map(String::as_ref) == Some("simulate") {
+        if let Err(e) = run_simulate(&matches.free[1..], matches.opt_str("for")) {
+            println!("ERROR {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // REPLAY
+    if matches.free.first().map(String::as_ref) == Some("replay") {
+        if let Err(e) = run_replay(&matches.free[1..], sysfs.as_deref()) {
+            println!("ERROR {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // CALIBRATE
+    if matches.free.first().map(String::as_ref) == Some("calibrate") {
+        if matches.opt_present("for") {
+            println!("ERROR calibrate takes step time from --settle, --for is total time of other commands");
+            process::exit(1);
+        }
+        let res = run_calibrate(
+            &matches.free[1..],
+            sysfs.as_deref(),
+            matches.opt_str("socket"),
+            matches.opt_str("settle"),
+        );
+        if let Err(e) = res {
+            println!("ERROR {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // CLIENT
+    if let Some(cmd) = matches.free.first() {
+        match cmd.as_ref() {
+            "status" | "set" | "pause" | "resume" | "release" | "rescan" => (),
+            _ => return print_help(&program, opts),
+        }
+        let socket = PathBuf::from(matches.opt_str("socket").unwrap_or(String::from(SOCKET)));
+        let res = run_client(
+            cmd,
+            &matches.free[1..],
+            &socket,
+            matches.opt_present("json"),
+            matches.opt_str("for"),
+        );
+        if let Err(e) = res {
+            println!("ERROR {}", e);
+            process::exit(1);
+        }
         return;
     }
 
@@ -323,21 +436,13 @@ fn main() {
         return print_help(&program, opts);
     }
 
-    match read_file(&toml_path) {
-        Ok(s) => {
-            match s.parse::<Value>() {
-                Ok(c) => {
-                    run_daemon(&c);
-                }
-                Err(e) => {
-                    println!("ERROR {:?}", e);
-                    return print_help(&program, opts);
-                }
-            };
-        }
-        Err(e) => {
-            println!("ERROR {:?}", e);
-            return print_help(&program, opts);
+    match Config::load(&toml_path, sysfs.as_deref()) {
+        Ok(conf) => daemon::run(&toml_path, sysfs.as_deref(), conf),
+        Err(errs) => {
+            for e in &errs {
+                println!("ERROR {}", e);
+            }
+            process::exit(1);
         }
     }
 }
@@ -0,0 +1,226 @@
+// Prometheus metrics endpoint
+// Minimal HTTP server answering GET /metrics from own thread
+
+use karlson::Hold;
+use karlson::Karlson;
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Scraper has this much time to send request and read response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longer requests are not read till the end
+const MAX_REQUEST: u64 = 8192;
+
+/// Metrics of all devices are formatted once per tick,
+/// scrapes are answered with this snapshot from own thread, so slow client would not stop control loop
+#[derive(Debug)]
+pub struct Metrics {
+    addr: SocketAddr,
+    snapshot: Arc<Mutex<String>>,
+}
+
+impl Metrics {
+    pub fn bind(addr: &str) -> Result<Metrics, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Can not bind metrics {} {}", addr, e))?;
+        let local = listener.local_addr().map_err(|e| format!("Can not bind metrics {} {}", addr, e))?;
+        let snapshot = Arc::new(Mutex::new(String::new()));
+        let s = snapshot.clone();
+        thread::spawn(move || serve(&listener, &s));
+        Ok(Metrics {
+            addr: local,
+            snapshot,
+        })
+    }
+
+    /// Address metrics are served on, port is known here if 0 was given
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Take snapshot of devices for following scrapes
+    pub fn update(&self, karlsons: &[Karlson], devices: &[Karlson]) {
+        *self.snapshot.lock().unwrap() = format_metrics(karlsons, devices);
+    }
+}
+
+/// Answer scrapes one by one
+fn serve(listener: &TcpListener, snapshot: &Mutex<String>) {
+    for stream in listener.incoming() {
+        let res = stream
+            .map_err(|e| e.to_string())
+            .and_then(|s| serve_client(&s, snapshot));
+        if let Err(e) = res {
+            println!("ERROR metrics client {}", e);
+        }
+    }
+}
+
+fn serve_client(stream: &TcpStream, snapshot: &Mutex<String>) -> Result<(), String> {
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    // Only request line matters, headers are read to not reset connection with unread data.
+    // Whole request must come in CLIENT_TIMEOUT and fit MAX_REQUEST
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+    let mut line = |buf: &mut String| -> Result<usize, String> {
+        let left = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+            .ok_or("request timeout")?;
+        stream.set_read_timeout(Some(left)).map_err(|e| e.to_string())?;
+        reader.read_line(buf).map_err(|e| e.to_string())
+    };
+    let mut request = String::new();
+    line(&mut request)?;
+    let mut header = String::new();
+    while line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", snapshot.lock().unwrap().clone()),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("Not found, try /metrics\n")),
+        _ => ("405 Method Not Allowed", String::from("Only GET is supported\n")),
+    };
+
+    let mut w = stream;
+    write!(
+        w,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    ).map_err(|e| e.to_string())
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn labels(k: &Karlson) -> String {
+    format!(
+        "dev_type=\"{}\",id=\"{}\",name=\"{}\"",
+        escape(&k.dev.dev_type),
+        k.dev.id,
+        escape(&k.dev.name)
+    )
+}
+
+/// Metric name, type, help and value of each device
+type Metric = (&'static str, &'static str, &'static str, fn(&Karlson) -> u64);
+
+const METRICS: &[Metric] = &[
+    ("karlson_pwm_percent", "gauge", "Current fan speed", |k| k.pwm_speed as u64),
+    ("karlson_pwm_target_percent", "gauge", "Fan speed chosen on last round", |k| k.pwm_target as u64),
+    ("karlson_pwm_min_percent", "gauge", "Minimum fan speed", |k| k.jam.pwm_min as u64),
+    ("karlson_pwm_ok_percent", "gauge", "Normal fan speed", |k| k.jam.pwm_ok as u64),
+    ("karlson_pwm_max_percent", "gauge", "Maximum fan speed", |k| k.jam.pwm_max as u64),
+    ("karlson_temp_ok_celsius", "gauge", "Optimal temperature", |k| k.jam.temp_ok as u64),
+    ("karlson_temp_hot_celsius", "gauge", "Hot temperature", |k| k.jam.temp_hot as u64),
+    ("karlson_temp_crit_celsius", "gauge", "Critical temperature", |k| k.jam.temp_crit as u64),
+    ("karlson_sensor_fault", "gauge", "1 if temperature is unknown and fan runs at fail-safe speed", |k| {
+        k.sensor_fault as u64
+    }),
+    ("karlson_fan_stalled", "gauge", "1 if fan does not spin as commanded", |k| k.stalled as u64),
+    ("karlson_fan_off", "gauge", "1 if fan is stopped in zero RPM mode", |k| k.fan_off as u64),
+    ("karlson_manual", "gauge", "1 if control is paused, forced or released by user", |k| {
+        (k.hold != Hold::Auto) as u64
+    }),
+    ("karlson_pwm_changes_total", "counter", "Fan speed changes", |k| k.counters.pwm_changes),
+    ("karlson_pwm_errors_total", "counter", "Failed fan speed reads and writes", |k| {
+        k.counters.pwm_errors
+    }),
+    ("karlson_temp_errors_total", "counter", "Failed temperature reads", |k| k.counters.temp_errors),
+];
+
+pub fn format_metrics(karlsons: &[Karlson], devices: &[Karlson]) -> String {
+    let mut res = String::new();
+    let all: Vec<&Karlson> = karlsons.iter().chain(devices.iter()).collect();
+
+    res.push_str("# HELP karlson_temp_celsius Temperature of thermometer\n");
+    res.push_str("# TYPE karlson_temp_celsius gauge\n");
+    for k in &all {
+        for (i, t) in k.temps.iter().enumerate() {
+            res.push_str(&format!(
+                "karlson_temp_celsius{{{},sensor=\"{}\"}} {}\n",
+                labels(k),
+                i,
+                t
+            ));
+        }
+    }
+
+    res.push_str("# HELP karlson_fan_rpm Fan speed from tachometer\n");
+    res.push_str("# TYPE karlson_fan_rpm gauge\n");
+    for k in &all {
+        if let Some(rpm) = k.rpm {
+            res.push_str(&format!("karlson_fan_rpm{{{}}} {}\n", labels(k), rpm));
+        }
+    }
+
+    for &(name, kind, help, value) in METRICS {
+        res.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for k in &all {
+            res.push_str(&format!("{}{{{}}} {}\n", name, labels(k), value(k)));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::Settings;
+    use karlson::KarlsonBuilder;
+    use std::io::Read;
+
+    /// Device without hardware, sys type would be looked up in real sysfs
+    fn fan() -> Karlson {
+        KarlsonBuilder::new("test", 3, "fan \"front\"")
+            .settings(&Settings::default())
+            .build()
+    }
+
+    #[test]
+    fn metrics_have_device_labels() {
+        let text = format_metrics(&[fan()], &[]);
+        let labels = "dev_type=\"test\",id=\"3\",name=\"fan \\\"front\\\"\"";
+        assert!(text.contains("# TYPE karlson_pwm_ok_percent gauge\n"));
+        assert!(text.contains(&format!("karlson_pwm_ok_percent{{{}}} 60\n", labels)));
+        assert!(text.contains(&format!("karlson_pwm_changes_total{{{}}} 0\n", labels)));
+        // Fan without tachometer has no RPM
+        assert!(!text.contains("karlson_fan_rpm{"));
+    }
+
+    fn get(m: &Metrics, path: &str) -> String {
+        let mut stream = TcpStream::connect(m.local_addr()).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn snapshot_is_served() {
+        let m = Metrics::bind("127.0.0.1:0").unwrap();
+        m.update(&[fan()], &[]);
+
+        let resp = get(&m, "/metrics");
+        assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(resp.ends_with(&format_metrics(&[fan()], &[])));
+        assert!(get(&m, "/").starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,149 @@
+// PID fan control strategy
+// Holds temperature at target_temp, pwm_ok is used as output bias
+
+use core::Settings;
+use karlson::Controller;
+use karlson::Decision;
+use karlson::Input;
+
+#[derive(Debug, Clone)]
+pub struct Pid {
+    target: Option<usize>,
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    /// Integral term in %, kept within pwm_min..pwm_max together with bias
+    integral: f64,
+    /// Previous temperature for derivative on measurement
+    last_temp: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(s: &Settings) -> Pid {
+        Pid {
+            target: s.target_temp,
+            kp: s.pid_kp,
+            ki: s.pid_ki,
+            kd: s.pid_kd,
+            integral: 0.0,
+            last_temp: None,
+        }
+    }
+}
+
+impl Controller for Pid {
+    fn decide(&mut self, inp: &Input) -> Decision {
+        let jam = inp.jam;
+        let target = self.target.unwrap_or(jam.temp_ok) as f64;
+        let temp = inp.temp as f64;
+        let min = jam.pwm_min as f64;
+        let max = jam.pwm_max.max(jam.pwm_min) as f64;
+        let bias = jam.pwm_ok as f64;
+
+        // Fan has to spin faster when temperature is above target
+        let err = temp - target;
+        let p = self.kp * err;
+
+        // Derivative on measurement, so target change does not kick the fan
+        let d = match self.last_temp {
+            Some(last) if inp.dt > 0.0 => self.kd * (temp - last) / inp.dt,
+            _ => 0.0,
+        };
+        self.last_temp = Some(temp);
+
+        if inp.dt > 0.0 {
+            self.integral += self.ki * err * inp.dt;
+        }
+
+        // Anti-windup, integral can only bring output to the limits
+        self.integral = self.integral
+            .min(max - bias - p - d)
+            .max(min - bias - p - d);
+
+        let out = (bias + p + self.integral + d).max(min).min(max);
+
+        Decision {
+            pwm: out.round() as usize,
+            reason: format!(
+                "pid target {}C P {:.1} I {:.1} D {:.1}",
+                target,
+                p,
+                self.integral,
+                d
+            ),
+        }
+    }
+
+    fn configure(&mut self, set: &Settings) {
+        self.target = set.target_temp;
+        self.kp = set.pid_kp;
+        self.ki = set.pid_ki;
+        self.kd = set.pid_kd;
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new((*self).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use karlson::Jam;
+    use std::collections::VecDeque;
+
+    fn jam(pwm_max: usize) -> Jam {
+        Jam {
+            pwm_ok: 60,
+            pwm_min: 21,
+            pwm_max,
+            temp_ok: 65,
+            temp_hot: 75,
+            temp_crit: 80,
+        }
+    }
+
+    fn decide(pid: &mut Pid, jam: &Jam, temp: usize, dt: f64) -> usize {
+        let tlog = VecDeque::new();
+        pid.decide(&Input {
+            temp,
+            tlog: &tlog,
+            tlog_max: temp,
+            tlog_size: 0,
+            pwm: 0,
+            idle: 0,
+            dt,
+            jam,
+        }).pwm
+    }
+
+    fn pid(kp: f64, ki: f64) -> Pid {
+        Pid::new(&Settings {
+            target_temp: Some(60),
+            pid_kp: kp,
+            pid_ki: ki,
+            pid_kd: 0.0,
+            ..Settings::default()
+        })
+    }
+
+    #[test]
+    fn output_is_kept_within_pwm_limits() {
+        let mut p = pid(3.0, 0.0);
+        assert_eq!(decide(&mut p, &jam(100), 65, 0.0), 75);
+        assert_eq!(decide(&mut p, &jam(100), 50, 0.0), 30);
+        assert_eq!(decide(&mut p, &jam(100), 40, 0.0), 21);
+        assert_eq!(decide(&mut p, &jam(80), 70, 0.0), 80);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut p = pid(1.0, 0.1);
+        for _ in 0..100 {
+            decide(&mut p, &jam(100), 70, 5.0);
+        }
+        assert_eq!(decide(&mut p, &jam(100), 70, 5.0), 100);
+        // Integral stopped at the limit, so fan slows down as soon as target is reached
+        assert_eq!(decide(&mut p, &jam(100), 60, 5.0), 90);
+    }
+}
@@ -0,0 +1,372 @@
+// Replay of recorded history
+// Recorded temperatures are fed through karlson with mock propeller,
+// so decisions of current code can be compared with recorded ones
+
+use clock::FakeClock;
+use config::Config;
+use core::Device;
+use core::Propeller;
+use core::Settings;
+use core::Thermometer;
+use core::read_file;
+use karlson::KarlsonBuilder;
+
+use serde_json;
+use serde_json::Value;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// One recorded spin of device, see history.rs
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub time: u64,
+    /// type#id like sys#0
+    pub device: String,
+    pub name: String,
+    pub temps: Vec<usize>,
+    pub pwm_before: usize,
+    /// Fan speed chosen by recorded code
+    pub target: usize,
+    pub reason: String,
+}
+
+/// Read CSV or newline-delimited JSON history, format is detected by content
+pub fn load(path: &Path) -> Result<Vec<Sample>, String> {
+    let pstr = path.to_string_lossy();
+    let text = read_file(&path.to_path_buf()).map_err(|e| format!("Can not read {} {}", pstr, e))?;
+    if text.trim_start().starts_with('{') {
+        return text.lines()
+            .enumerate()
+            .filter(|&(_, l)| !l.trim().is_empty())
+            .map(|(i, l)| {
+                sample_json(l).map_err(|e| format!("{}:{} {}", pstr, i + 1, e))
+            })
+            .collect();
+    }
+
+    let mut lines = text.lines().enumerate();
+    let header = match lines.next() {
+        Some((_, h)) => csv_split(h),
+        None => return Ok(Vec::new()),
+    };
+    lines
+        .filter(|&(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            sample_csv(&header, l).map_err(|e| format!("{}:{} {}", pstr, i + 1, e))
+        })
+        .collect()
+}
+
+fn sample_json(line: &str) -> Result<Sample, String> {
+    let v: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let uint = |k: &str| v[k].as_u64().ok_or(format!("{} is required", k));
+    let string = |k: &str| v[k].as_str().map(String::from).ok_or(format!("{} is required", k));
+    Ok(Sample {
+        time: uint("time")?,
+        device: string("device")?,
+        name: string("name").unwrap_or_default(),
+        temps: v["temps"]
+            .as_array()
+            .map(|a| a.iter().filter_map(|t| t.as_u64()).map(|t| t as usize).collect())
+            .unwrap_or_default(),
+        pwm_before: uint("pwm_before")? as usize,
+        target: uint("target")? as usize,
+        reason: string("reason").unwrap_or_default(),
+    })
+}
+
+fn sample_csv(header: &[String], line: &str) -> Result<Sample, String> {
+    let fields = csv_split(line);
+    let get = |k: &str| {
+        header
+            .iter()
+            .position(|h| h == k)
+            .and_then(|i| fields.get(i))
+            .map(|f| f.as_str())
+            .ok_or(format!("{} is required", k))
+    };
+    let uint = |k: &str| {
+        get(k)?
+            .parse::<u64>()
+            .map_err(|_| format!("{} is not a number", k))
+    };
+
+    let mut temps = Vec::new();
+    for t in get("temps")?.split_whitespace() {
+        temps.push(t.parse::<usize>().map_err(|_| format!("invalid temperature {}", t))?);
+    }
+
+    Ok(Sample {
+        time: uint("time")?,
+        device: get("device")?.to_string(),
+        name: get("name").unwrap_or("").to_string(),
+        temps,
+        pwm_before: uint("pwm_before")? as usize,
+        target: uint("target")? as usize,
+        reason: get("reason").unwrap_or("").to_string(),
+    })
+}
+
+/// Split CSV line, double quotes escape commas and "" is a quote inside quoted field
+fn csv_split(line: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => res.push(::std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    res.push(field);
+    res
+}
+
+/// Recorded state of device at current tick
+#[derive(Debug)]
+struct Tape {
+    temps: Vec<usize>,
+    pwm: usize,
+}
+
+#[derive(Debug, Clone)]
+struct ReplayPropeller {
+    tape: Rc<RefCell<Tape>>,
+}
+
+impl Propeller for ReplayPropeller {
+    /// Fan speed as it was recorded, so every decision starts from the real state
+    fn pwm(&self) -> Result<usize, String> {
+        Ok(self.tape.borrow().pwm)
+    }
+
+    fn pwm_set(&self, val: usize) -> Result<usize, String> {
+        Ok(val)
+    }
+
+    fn box_clone(&self) -> Box<dyn Propeller> {
+        Box::new((*self).clone())
+    }
+
+    fn configure(&mut self, _set: &Settings) {}
+
+    fn restore(&self) -> Result<String, String> {
+        Ok(String::from("replay"))
+    }
+
+    fn pwm_leave(&self, val: usize) -> Result<usize, String> {
+        Ok(val)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ReplayThermometer {
+    tape: Rc<RefCell<Tape>>,
+    idx: usize,
+}
+
+impl Thermometer for ReplayThermometer {
+    /// Recorded temperature, error if it was not read at that tick
+    fn temp(&self) -> Result<usize, String> {
+        self.tape
+            .borrow()
+            .temps
+            .get(self.idx)
+            .cloned()
+            .ok_or(String::from("temperature was not recorded"))
+    }
+
+    fn box_clone(&self) -> Box<dyn Thermometer> {
+        Box::new((*self).clone())
+    }
+}
+
+/// Settings device would get from config, [[devices]] are selected by position
+fn settings_for<'a>(conf: &'a Config, dev: &Device) -> &'a Settings {
+    match dev.dev_type.as_ref() {
+        "dev" => conf.devices.get(dev.id as usize).unwrap_or(&conf.defaults),
+        _ => conf.settings_for(dev),
+    }
+}
+
+/// Replay samples of one device, return number of ticks where decision differs
+fn replay_device(conf: &Config, samples: &[&Sample]) -> usize {
+    let first = samples[0];
+    let (dev_type, id) = match first.device.find('#') {
+        Some(i) => (&first.device[..i], first.device[i + 1..].parse::<i32>().unwrap_or(0)),
+        None => (first.device.as_str(), 0),
+    };
+    let tape = Rc::new(RefCell::new(Tape {
+        temps: first.temps.clone(),
+        pwm: first.pwm_before,
+    }));
+    let terms = samples.iter().map(|s| s.temps.len()).max().unwrap_or(1).max(1);
+
+    // Recorded sys devices must not be looked up in sysfs, so karlson gets its own type
+    let clock = FakeClock::new();
+    let mut b = KarlsonBuilder::new("replay", id, &first.name)
+        .settings(settings_for(conf, &Device::new(dev_type, id, &first.name)))
+        .propeller(Box::new(ReplayPropeller { tape: tape.clone() }))
+        .clock(Box::new(clock.clone()));
+    for idx in 0..terms {
+        b = b.thermometer(Box::new(ReplayThermometer {
+            tape: tape.clone(),
+            idx,
+        }));
+    }
+    let mut k = b.build();
+    let mut diffs = 0;
+
+    println!("{} {}", first.device, first.name);
+    println!(
+        "{:>8} {:<12} {:>6} {:>8} {:>8}  REASON (RECORDED / REPLAY)",
+        "SEC",
+        "TEMPS",
+        "PWM",
+        "RECORDED",
+        "REPLAY"
+    );
+    for s in samples {
+        {
+            let mut t = tape.borrow_mut();
+            t.temps = s.temps.clone();
+            t.pwm = s.pwm_before;
+        }
+        let sec = s.time.saturating_sub(first.time);
+        clock.set(Duration::from_secs(sec));
+        k.spin();
+
+        let temps: Vec<String> = s.temps.iter().map(|t| t.to_string()).collect();
+        let mark = if k.pwm_target != s.target {
+            diffs += 1;
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            "{:>8} {:<12} {:>5}% {:>7}% {:>7}%{} {} / {}",
+            sec,
+            temps.join(" "),
+            s.pwm_before,
+            s.target,
+            k.pwm_target,
+            mark,
+            s.reason,
+            k.reason
+        );
+    }
+    diffs
+}
+
+/// Replay trace for all devices in it or only for one, return number of different decisions
+pub fn replay(conf: &Config, samples: &[Sample], device: Option<&str>) -> Result<usize, String> {
+    let mut order: Vec<&str> = Vec::new();
+    let mut by_dev: HashMap<&str, Vec<&Sample>> = HashMap::new();
+    for s in samples {
+        if device.is_some_and(|d| d != s.device) {
+            continue;
+        }
+        if !by_dev.contains_key(s.device.as_str()) {
+            order.push(&s.device);
+        }
+        by_dev.entry(&s.device).or_default().push(s);
+    }
+    if order.is_empty() {
+        return Err(String::from("No samples to replay"));
+    }
+
+    let mut diffs = 0;
+    for d in order {
+        let samples = &by_dev[d];
+        let n = replay_device(conf, samples);
+        println!("{} ticks, {} decisions differ\n", samples.len(), n);
+        diffs += n;
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use toml::Value as Toml;
+
+    const CSV: &str = "time,device,name,temps,pwm_before,pwm,target,reason\n\
+                       100,sys#0,\"fan \"\"front\"\", left\",70 66,60,60,60,\"temp is ok\"\n\
+                       \n\
+                       105,sys#0,\"fan \"\"front\"\", left\",71 66,60,60,60,\"temp is ok\"\n";
+
+    fn file(name: &str, text: &str) -> PathBuf {
+        let p = env::temp_dir().join(format!("karlson-{}-{}", name, process::id()));
+        fs::write(&p, text).unwrap();
+        p
+    }
+
+    fn load_str(name: &str, text: &str) -> Vec<Sample> {
+        let p = file(name, text);
+        let samples = load(&p).unwrap();
+        fs::remove_file(&p).unwrap();
+        samples
+    }
+
+    #[test]
+    fn csv_fields_are_unquoted() {
+        assert_eq!(csv_split("a,\"b,c\",\"d \"\"e\"\"\",,f"), vec!["a", "b,c", "d \"e\"", "", "f"]);
+    }
+
+    #[test]
+    fn csv_and_json_traces_are_loaded() {
+        let samples = load_str("trace.csv", CSV);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].name, "fan \"front\", left");
+        assert_eq!(samples[1].temps, vec![71, 66]);
+        assert_eq!((samples[1].time, samples[1].pwm_before, samples[1].target), (105, 60, 60));
+
+        let p = file(
+            "trace.json",
+            "{\"time\":100,\"device\":\"nv#1\",\"temps\":[58],\"pwm_before\":40,\"target\":42}\n",
+        );
+        let samples = load(&p).unwrap();
+        fs::remove_file(&p).unwrap();
+        assert_eq!(samples[0].device, "nv#1");
+        assert_eq!(samples[0].name, "");
+        assert_eq!(samples[0].target, 42);
+    }
+
+    #[test]
+    fn broken_line_is_reported_with_number() {
+        let p = file("broken.csv", &CSV.replace("71 66", "71 hot"));
+        let err = load(&p).unwrap_err();
+        fs::remove_file(&p).unwrap();
+        assert!(err.ends_with(":4 invalid temperature hot"), "{}", err);
+
+        let p = file("broken.json", "{\"time\":100,\"device\":\"sys#0\",\"pwm_before\":40}\n");
+        let err = load(&p).unwrap_err();
+        fs::remove_file(&p).unwrap();
+        assert!(err.ends_with(":1 target is required"), "{}", err);
+    }
+
+    #[test]
+    fn replay_counts_different_decisions() {
+        let conf = Config::from(&Toml::Table(Default::default())).unwrap();
+        let mut samples = load_str("replay.csv", CSV);
+        assert_eq!(replay(&conf, &samples, None), Ok(0));
+
+        samples[1].target = 80;
+        assert_eq!(replay(&conf, &samples, Some("sys#0")), Ok(1));
+        assert!(replay(&conf, &samples, Some("sys#1")).is_err());
+    }
+}
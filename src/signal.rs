@@ -0,0 +1,40 @@
+// Unix signals handling
+// Handler only stores signal number, daemon loop checks it between ticks
+
+use libc;
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+static SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    SIGNAL.store(sig as usize, Ordering::SeqCst);
+}
+
+/// Start catching SIGTERM, SIGINT and SIGHUP (reload)
+pub fn listen() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+/// Return last received signal if any and reset it
+pub fn take() -> Option<i32> {
+    match SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        s => Some(s as i32),
+    }
+}
+
+pub fn name(sig: i32) -> &'static str {
+    match sig {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGINT => "SIGINT",
+        libc::SIGHUP => "SIGHUP",
+        _ => "UNKNOWN",
+    }
+}
@@ -0,0 +1,229 @@
+// Offline thermal simulator
+// Karlson drives a simulated fan and heatsink faster than real time,
+// so strategies and settings can be compared before they reach real rigs
+
+use clock::FakeClock;
+use config::SimConfig;
+use core::Propeller;
+use core::Settings;
+use core::Thermometer;
+use karlson::KarlsonBuilder;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Seconds between spins, the same as daemon tick
+pub const TICK: u64 = 5;
+
+/// First-order thermal model:
+/// thermal_mass * dT/dt = load - (passive_cooling + fan_cooling * pwm / 100) * (T - ambient)
+#[derive(Debug)]
+pub struct Rig {
+    conf: SimConfig,
+    /// Seconds since start
+    time: u64,
+    temp: f64,
+    pwm: usize,
+}
+
+impl Rig {
+    pub fn new(conf: &SimConfig) -> Rig {
+        Rig {
+            conf: conf.clone(),
+            time: 0,
+            temp: conf.start_temp.unwrap_or(conf.ambient),
+            pwm: 0,
+        }
+    }
+
+    /// Heat load at current time
+    fn load(&self) -> f64 {
+        self.conf
+            .load
+            .iter()
+            .rev()
+            .find(|&&(t, _)| t <= self.time)
+            .map(|&(_, w)| w)
+            .unwrap_or(0.0)
+    }
+
+    /// Integrate model with 1 second steps
+    fn advance(&mut self, sec: u64) {
+        for _ in 0..sec {
+            let c = &self.conf;
+            let cooling = c.passive_cooling + c.fan_cooling * self.pwm as f64 / 100.0;
+            let heat = self.load() - cooling * (self.temp - c.ambient);
+            self.temp += heat / c.thermal_mass;
+            self.time += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SimPropeller {
+    rig: Rc<RefCell<Rig>>,
+    min: usize,
+    /// Zero RPM mode, fan may be stopped below min
+    zero: bool,
+}
+
+impl Propeller for SimPropeller {
+    fn pwm(&self) -> Result<usize, String> {
+        Ok(self.rig.borrow().pwm)
+    }
+
+    fn pwm_set(&self, val: usize) -> Result<usize, String> {
+        let mut nval = val.min(100);
+        if val < self.min && !(val == 0 && self.zero) {
+            nval = self.min;
+        }
+        self.rig.borrow_mut().pwm = nval;
+        self.pwm()
+    }
+
+    fn box_clone(&self) -> Box<dyn Propeller> {
+        Box::new((*self).clone())
+    }
+
+    fn configure(&mut self, set: &Settings) {
+        self.min = set.pwm_min;
+        self.zero = set.zero_rpm_temp.is_some();
+    }
+
+    fn restore(&self) -> Result<String, String> {
+        Ok(String::from("simulation"))
+    }
+
+    fn pwm_leave(&self, val: usize) -> Result<usize, String> {
+        self.pwm_set(val)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SimThermometer {
+    rig: Rc<RefCell<Rig>>,
+}
+
+impl Thermometer for SimThermometer {
+    fn temp(&self) -> Result<usize, String> {
+        Ok(self.rig.borrow().temp.round().max(1.0) as usize)
+    }
+
+    fn box_clone(&self) -> Box<dyn Thermometer> {
+        Box::new((*self).clone())
+    }
+}
+
+/// What happened during simulation
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub strategy: String,
+    pub seconds: u64,
+    /// Seconds with temperature above temp_hot
+    pub above_hot: u64,
+    pub peak_temp: f64,
+    pub pwm_changes: u64,
+    /// Time weighted average fan speed
+    pub avg_pwm: f64,
+}
+
+/// Run karlson with settings against thermal model for provided time
+pub fn simulate(set: &Settings, conf: &SimConfig, seconds: u64) -> Report {
+    let rig = Rc::new(RefCell::new(Rig::new(conf)));
+    let clock = FakeClock::new();
+    let mut k = KarlsonBuilder::new("sim", 0, "simulated")
+        .settings(set)
+        .propeller(Box::new(SimPropeller {
+            rig: rig.clone(),
+            min: set.pwm_min,
+            zero: set.zero_rpm_temp.is_some(),
+        }))
+        .thermometer(Box::new(SimThermometer { rig: rig.clone() }))
+        .clock(Box::new(clock.clone()))
+        .build();
+    let mut rep = Report {
+        strategy: set.strategy.clone(),
+        seconds: 0,
+        above_hot: 0,
+        peak_temp: rig.borrow().temp,
+        pwm_changes: 0,
+        avg_pwm: 0.0,
+    };
+    let mut pwm_sum = 0.0;
+
+    while rep.seconds < seconds {
+        k.spin();
+
+        let mut r = rig.borrow_mut();
+        r.advance(TICK);
+        clock.advance(Duration::from_secs(TICK));
+        rep.seconds += TICK;
+        pwm_sum += (r.pwm as u64 * TICK) as f64;
+        if r.temp > set.temp_hot as f64 {
+            rep.above_hot += TICK;
+        }
+        rep.peak_temp = rep.peak_temp.max(r.temp);
+    }
+
+    rep.pwm_changes = k.counters.pwm_changes;
+    rep.avg_pwm = pwm_sum / rep.seconds.max(1) as f64;
+    rep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rig_settles_at_equilibrium() {
+        let mut rig = Rig::new(&SimConfig::default());
+        rig.pwm = 100;
+        rig.advance(20000);
+        // ambient + load / (passive_cooling + fan_cooling)
+        assert!((rig.temp - (25.0 + 150.0 / 7.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn load_steps_are_applied() {
+        let mut rig = Rig::new(&SimConfig {
+            load: vec![(0, 0.0), (100, 150.0)],
+            ..SimConfig::default()
+        });
+        rig.advance(50);
+        assert_eq!(rig.load(), 0.0);
+        assert_eq!(rig.temp, 25.0);
+        rig.advance(100);
+        assert_eq!(rig.load(), 150.0);
+        assert!(rig.temp > 25.0);
+    }
+
+    #[test]
+    fn fan_keeps_pwm_min_unless_zero_rpm() {
+        let p = SimPropeller {
+            rig: Rc::new(RefCell::new(Rig::new(&SimConfig::default()))),
+            min: 21,
+            zero: false,
+        };
+        assert_eq!(p.pwm_set(5), Ok(21));
+        assert_eq!(p.pwm_set(0), Ok(21));
+        let p = SimPropeller { zero: true, ..p };
+        assert_eq!(p.pwm_set(0), Ok(0));
+        assert_eq!(p.pwm_set(5), Ok(21));
+    }
+
+    #[test]
+    fn pid_holds_target_temperature() {
+        let set = Settings {
+            strategy: String::from("pid"),
+            target_temp: Some(60),
+            ..Settings::default()
+        };
+        let rep = simulate(&set, &SimConfig::default(), 3600);
+        assert_eq!(rep.seconds, 3600);
+        assert_eq!(rep.above_hot, 0);
+        assert!(rep.peak_temp < 62.0);
+        // Stopped fan would heat device up to ambient + load / passive_cooling
+        assert!(rep.avg_pwm > 21.0 && rep.avg_pwm < 100.0);
+    }
+}